@@ -0,0 +1,347 @@
+//! An async client on top of the event loop, enabled by the `async` feature.
+//!
+//! `AsyncConnection::connect` runs an event loop for the connection on a thread of its own, so
+//! no executor thread ever blocks on the socket. The connection yields incoming messages as a
+//! `Stream` and sends through a `Sink`.
+
+use super::{Builder, SocketHandle};
+use communication::Sender;
+use factory::Factory;
+use futures_channel::{mpsc, oneshot};
+use futures_core::{Future, Stream};
+use futures_sink::Sink;
+use handler::Handler;
+use handshake::Handshake;
+use io::QueueRoom;
+use message::Message;
+use protocol::CloseCode;
+use result::{Result, Error, Kind};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Hands the open connection and its messages over to the async side.
+pub struct AsyncFactory {
+    open: Option<oneshot::Sender<Result<Sender>>>,
+    messages: mpsc::UnboundedSender<Result<Message>>,
+}
+
+impl Factory for AsyncFactory {
+    type Handler = AsyncHandler;
+
+    fn connection_made(&mut self, out: Sender) -> AsyncHandler {
+        AsyncHandler {
+            out,
+            open: self.open.take(),
+            messages: self.messages.clone(),
+        }
+    }
+}
+
+/// The `Handler` of an `AsyncConnection`.
+pub struct AsyncHandler {
+    out: Sender,
+    open: Option<oneshot::Sender<Result<Sender>>>,
+    messages: mpsc::UnboundedSender<Result<Message>>,
+}
+
+impl Handler for AsyncHandler {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        if let Some(open) = self.open.take() {
+            if open.send(Ok(self.out.clone())).is_err() {
+                // nobody waits for the connection anymore
+                return self.out.close(CloseCode::Away);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if self.messages.unbounded_send(Ok(msg)).is_err() {
+            return self.out.close(CloseCode::Away);
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        self.messages.close_channel();
+    }
+
+    fn on_error(&mut self, err: Error) {
+        match self.open.take() {
+            Some(open) => {
+                let _ = open.send(Err(err));
+            }
+            None => {
+                let _ = self.messages.unbounded_send(Err(err));
+            }
+        }
+    }
+}
+
+/// Resolves to the `AsyncConnection` once it is open, or to the error that kept it from opening.
+pub struct Connecting {
+    open: oneshot::Receiver<Result<Sender>>,
+    messages: Option<mpsc::UnboundedReceiver<Result<Message>>>,
+    handle: Option<SocketHandle<AsyncFactory>>,
+    room: Arc<QueueRoom>,
+}
+
+impl Future for Connecting {
+    type Output = Result<AsyncConnection>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<AsyncConnection>> {
+        let opened = match Pin::new(&mut self.open).poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Ok(opened)) => opened,
+            Poll::Ready(Err(_)) => Err(Error::new(Kind::Internal, "The event loop stopped before the connection opened.")),
+        };
+        Poll::Ready(opened.map(|sender| {
+            AsyncConnection {
+                sender,
+                messages: self.messages.take().expect("Connecting polled after completion."),
+                handle: self.handle.take(),
+                room: self.room.clone(),
+                pending: None,
+            }
+        }))
+    }
+}
+
+impl fmt::Debug for Connecting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connecting").finish()
+    }
+}
+
+/// An open client connection for async code.
+///
+/// The sink holds one message at a time. It is ready again once that message is in the queue of
+/// the event loop. While the queue of `Settings::queue_size` commands is full it stays pending
+/// until the event loop takes commands out. Dropping the connection stops its event loop.
+pub struct AsyncConnection {
+    sender: Sender,
+    messages: mpsc::UnboundedReceiver<Result<Message>>,
+    handle: Option<SocketHandle<AsyncFactory>>,
+    // wakes the sink once the event loop made room in its queue
+    room: Arc<QueueRoom>,
+    // the message waiting for room in the queue of the event loop
+    pending: Option<Message>,
+}
+
+impl AsyncConnection {
+    /// Connect to `url` with the default settings.
+    pub fn connect<U: Into<String>>(url: U) -> Connecting {
+        AsyncConnection::connect_with(&Builder::new(), url)
+    }
+
+    /// Connect to `url` with the settings and codec of `builder`.
+    pub fn connect_with<U: Into<String>>(builder: &Builder, url: U) -> Connecting {
+        let (open_tx, open_rx) = oneshot::channel();
+        let (messages_tx, messages_rx) = mpsc::unbounded();
+        let factory = AsyncFactory {
+            open: Some(open_tx),
+            messages: messages_tx,
+        };
+
+        let mut room = Arc::new(QueueRoom::default());
+        let handle = builder.build(factory).and_then(|mut socket| {
+            room = socket.handler.queue_room();
+            socket.connect(url.into())?;
+            socket.spawn()
+        });
+        let (open_rx, handle) = match handle {
+            Ok(handle) => (open_rx, Some(handle)),
+            Err(err) => {
+                // the factory is gone with the socket, answer in its place
+                let (open_tx, open_rx) = oneshot::channel();
+                let _ = open_tx.send(Err(err));
+                (open_rx, None)
+            }
+        };
+
+        Connecting {
+            open: open_rx,
+            messages: Some(messages_rx),
+            handle,
+            room,
+        }
+    }
+
+    /// The sender of the connection, for anything beyond sending messages.
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    /// Close the connection with `code`, the stream ends once the close handshake is done.
+    pub fn close(&self, code: CloseCode) -> Result<()> {
+        self.sender.close(code)
+    }
+}
+
+impl Stream for AsyncConnection {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Message>>> {
+        Pin::new(&mut self.messages).poll_next(cx)
+    }
+}
+
+impl Sink<Message> for AsyncConnection {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<()> {
+        debug_assert!(self.pending.is_none(), "start_send called before poll_ready.");
+        self.pending = Some(msg);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let msg = match self.pending.take() {
+            Some(msg) => msg,
+            None => return Poll::Ready(Ok(())),
+        };
+        let msg = match self.sender.try_send(msg) {
+            Ok(None) => return Poll::Ready(Ok(())),
+            Ok(Some(msg)) => msg,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+        // wait for the event loop to take commands out of its queue, trying once more in case
+        // it did so before the waker was in place
+        self.room.register(cx.waker());
+        match self.sender.try_send(msg) {
+            Ok(None) => Poll::Ready(Ok(())),
+            Ok(Some(msg)) => {
+                self.pending = Some(msg);
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(self.sender.close(CloseCode::Normal)),
+            polled => polled,
+        }
+    }
+}
+
+impl Drop for AsyncConnection {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.shutdown();
+        }
+    }
+}
+
+impl fmt::Debug for AsyncConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncConnection")
+            .field("connection_id", &self.sender.connection_id())
+            .finish()
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use communication::Signal;
+    use mio;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Wake, Waker};
+    use util::Token;
+
+    struct Count(AtomicUsize);
+
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn open_then_stream() {
+        let (queue_tx, queue_rx) = mio::channel::sync_channel(8);
+        let (open_tx, open_rx) = oneshot::channel();
+        let (messages_tx, messages_rx) = mpsc::unbounded();
+        let mut factory = AsyncFactory { open: Some(open_tx), messages: messages_tx };
+        let mut connecting = Connecting { open: open_rx, messages: Some(messages_rx), handle: None, room: Arc::new(QueueRoom::default()) };
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut handler = factory.connection_made(Sender::new(Token(1), queue_tx, 5));
+        assert!(Pin::new(&mut connecting).poll(&mut cx).is_pending());
+
+        handler.on_open(Handshake {
+            peer_addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr: "127.0.0.1:2".parse().unwrap(),
+            endpoint: ::connection::Endpoint::Client("127.0.0.1:1".into()),
+            connection_id: 5,
+            accepted_at: ::std::time::SystemTime::now(),
+            request: None,
+            response: None,
+        }).unwrap();
+        let mut conn = match Pin::new(&mut connecting).poll(&mut cx) {
+            Poll::Ready(Ok(conn)) => conn,
+            _ => panic!("connection did not open"),
+        };
+        assert_eq!(conn.sender().connection_id(), 5);
+
+        handler.on_message(Message::text("hi")).unwrap();
+        handler.on_close(CloseCode::Normal, "");
+        match Pin::new(&mut conn).poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(msg))) => assert_eq!(msg, Message::text("hi")),
+            _ => panic!("message did not arrive"),
+        }
+        assert!(match Pin::new(&mut conn).poll_next(&mut cx) {
+            Poll::Ready(None) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn sink_waits_for_room() {
+        let (queue_tx, queue_rx) = mio::channel::sync_channel(1);
+        let (messages_tx, messages_rx) = mpsc::unbounded();
+        let room = Arc::new(QueueRoom::default());
+        let mut conn = AsyncConnection {
+            sender: Sender::new(Token(1), queue_tx, 5),
+            messages: messages_rx,
+            handle: None,
+            room: room.clone(),
+            pending: None,
+        };
+        let woken = Arc::new(Count(AtomicUsize::new(0)));
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut conn).poll_ready(&mut cx).is_ready());
+        Pin::new(&mut conn).start_send(Message::text("one")).unwrap();
+        assert!(Pin::new(&mut conn).poll_ready(&mut cx).is_ready());
+        Pin::new(&mut conn).start_send(Message::text("two")).unwrap();
+
+        // the queue is full, the sink holds on to the second message instead of blocking
+        assert!(Pin::new(&mut conn).poll_ready(&mut cx).is_pending());
+        assert!(Pin::new(&mut conn).poll_flush(&mut cx).is_pending());
+        assert_eq!(woken.0.load(Ordering::SeqCst), 0);
+
+        // the event loop takes a command out and wakes the sink once
+        assert_eq!(queue_rx.try_recv().unwrap().connection_id(), 5);
+        room.wake();
+        assert_eq!(woken.0.load(Ordering::SeqCst), 1);
+        assert!(match Pin::new(&mut conn).poll_flush(&mut cx) {
+            Poll::Ready(Ok(())) => true,
+            _ => false,
+        });
+        match queue_rx.try_recv().unwrap().signal() {
+            Signal::Message(msg) => assert_eq!(msg, Message::text("two")),
+            signal => panic!("unexpected {:?}", signal),
+        }
+    }
+}
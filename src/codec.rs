@@ -0,0 +1,866 @@
+//! The codec module provides the framing used to split the byte stream of a connection into frames.
+
+use super::Settings;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use connection::Endpoint;
+use frame::Frame;
+use protocol::OpCode;
+use rand;
+use result::{Result, Error, Kind};
+use std::io::Cursor;
+use std::str::from_utf8;
+use std::sync::Arc;
+
+/// Creates a fresh codec for every new connection.
+pub type NewCodec = Arc<dyn Fn() -> Box<dyn Codec + Send> + Send + Sync>;
+
+/// A trait for framing messages on top of the byte stream of a connection.
+///
+/// Every connection owns its own codec, so implementations may keep state between calls.
+/// The opcode of a decoded frame decides whether it reaches the `Handler` as a text or a binary
+/// message.
+pub trait Codec {
+    /// Decode the next frame from the unread part of `input`.
+    ///
+    /// On success the position of `input` must be advanced past the consumed bytes. Return
+    /// `Ok(None)` when `input` does not yet hold a complete frame, the remaining bytes will be
+    /// kept in the input buffer until more data arrives.
+    fn decode(&mut self, input: &mut Cursor<Vec<u8>>) -> Result<Option<Frame>>;
+
+    /// Encode `frame` by appending its bytes to `output`.
+    fn encode(&mut self, frame: Frame, output: &mut Vec<u8>) -> Result<()>;
+
+    /// Called once when the codec is attached to a connection so that limits from the socket
+    /// settings can be applied.
+    #[inline]
+    fn configure(&mut self, _: &Settings) {}
+
+    /// Called once it is known whether the connection dialed out or accepted the other endpoint.
+    #[inline]
+    fn set_endpoint(&mut self, _: &Endpoint) {}
+
+    /// Whether connections have to complete the HTTP upgrade handshake of a WebSocket before
+    /// any frames are exchanged.
+    #[inline]
+    fn requires_upgrade(&self) -> bool {
+        false
+    }
+
+    /// Whether the codec can carry control frames such as close frames.
+    ///
+    /// Connections using a codec without control frames close by shutting down the writing half
+    /// of the socket once all buffered data has been sent, the other endpoint sees the end of
+    /// the stream in place of a close frame.
+    #[inline]
+    fn supports_control_frames(&self) -> bool {
+        false
+    }
+
+    /// Whether the codec can mark frames as fragments of a larger message.
+    ///
+    /// Connections only split messages larger than `Settings::fragment_size` over codecs that
+    /// support it.
+    #[inline]
+    fn supports_fragmentation(&self) -> bool {
+        false
+    }
+
+    /// Whether the codec can flag frames as compressed.
+    ///
+    /// Connections only negotiate compression over codecs that support it.
+    #[inline]
+    fn supports_compression(&self) -> bool {
+        false
+    }
+}
+
+/// A codec without any framing.
+///
+/// Whatever bytes are available after a read are delivered as a single message and messages
+/// are written to the socket as they are. The type of the received messages is taken from
+/// `Settings::raw_opcode`.
+#[derive(Debug, Clone, Copy)]
+pub struct Raw {
+    opcode: OpCode,
+}
+
+impl Default for Raw {
+    fn default() -> Raw {
+        Raw { opcode: Settings::default().raw_opcode }
+    }
+}
+
+impl Codec for Raw {
+    fn decode(&mut self, input: &mut Cursor<Vec<u8>>) -> Result<Option<Frame>> {
+        let pos = input.position() as usize;
+        if pos >= input.get_ref().len() {
+            return Ok(None);
+        }
+
+        let data = input.get_ref()[pos..].to_vec();
+        input.set_position(input.get_ref().len() as u64);
+        Ok(Some(Frame::message(data, self.opcode)))
+    }
+
+    fn encode(&mut self, frame: Frame, output: &mut Vec<u8>) -> Result<()> {
+        output.extend(frame.into_data());
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &Settings) {
+        self.opcode = settings.raw_opcode;
+    }
+}
+
+/// The native frame format of XnetSocket.
+///
+/// Every frame starts with a one byte header holding the opcode in its low four bits, `0x40` as
+/// the flag for a compressed payload and `0x20` as the flag for a fragment that is followed by
+/// more fragments of its message. The other bits are reserved and must be zero.
+/// The header is followed by the length of the payload as a four byte big-endian integer and
+/// the payload itself. Frames larger than
+/// `Settings::max_frame_length` are rejected with a capacity error.
+#[derive(Debug, Clone, Copy)]
+pub struct Framed {
+    max_frame_length: usize,
+}
+
+impl Default for Framed {
+    fn default() -> Framed {
+        Framed { max_frame_length: Settings::default().max_frame_length }
+    }
+}
+
+impl Codec for Framed {
+    fn decode(&mut self, input: &mut Cursor<Vec<u8>>) -> Result<Option<Frame>> {
+        let pos = input.position() as usize;
+        let (opcode, compressed, finished, length) = {
+            let data = &input.get_ref()[pos..];
+            if data.len() < 5 {
+                return Ok(None);
+            }
+            if data[0] & 0x90 != 0 {
+                return Err(Error::new(Kind::Protocol, format!("Reserved bits set in frame header {:#x}.", data[0])));
+            }
+            (OpCode::from(data[0] & 0x0F), data[0] & 0x40 != 0, data[0] & 0x20 == 0, BigEndian::read_u32(&data[1..5]) as usize)
+        };
+
+        if let OpCode::Bad = opcode {
+            return Err(Error::new(Kind::Protocol, "Encountered invalid opcode."));
+        }
+        if length > self.max_frame_length {
+            return Err(Error::new(Kind::Capacity, format!("Frame length {} exceeds the maximum frame length of {}.", length, self.max_frame_length)));
+        }
+
+        let start = pos + 5;
+        let end = start + length;
+        if input.get_ref().len() < end {
+            return Ok(None);
+        }
+
+        let data = input.get_ref()[start..end].to_vec();
+        input.set_position(end as u64);
+        let mut frame = Frame::new(opcode, data);
+        frame.set_compressed(compressed).set_final(finished);
+        Ok(Some(frame))
+    }
+
+    fn encode(&mut self, frame: Frame, output: &mut Vec<u8>) -> Result<()> {
+        if frame.len() > u32::MAX as usize {
+            return Err(Error::new(Kind::Capacity, "Frame is too large for a 32 bit length."));
+        }
+        let mut header: u8 = frame.opcode().into();
+        if frame.is_compressed() {
+            header |= 0x40;
+        }
+        if !frame.is_final() {
+            header |= 0x20;
+        }
+        output.push(header);
+        output.write_u32::<BigEndian>(frame.len() as u32)?;
+        output.extend(frame.into_data());
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &Settings) {
+        self.max_frame_length = settings.max_frame_length;
+    }
+
+    #[inline]
+    fn supports_control_frames(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn supports_fragmentation(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn supports_compression(&self) -> bool {
+        true
+    }
+}
+
+/// The frame format of RFC 6455 WebSockets.
+///
+/// Connections using this codec open with the HTTP upgrade handshake, which lets them talk to
+/// browsers and other WebSocket endpoints. Clients mask every frame they send and servers
+/// reject unmasked frames. Frames larger than `Settings::max_frame_length` are rejected with a
+/// capacity error.
+#[derive(Debug, Clone, Copy)]
+pub struct WebSocket {
+    max_frame_length: usize,
+    client: bool,
+}
+
+impl Default for WebSocket {
+    fn default() -> WebSocket {
+        WebSocket {
+            max_frame_length: Settings::default().max_frame_length,
+            client: false,
+        }
+    }
+}
+
+impl Codec for WebSocket {
+    fn decode(&mut self, input: &mut Cursor<Vec<u8>>) -> Result<Option<Frame>> {
+        let pos = input.position() as usize;
+        let (opcode, finished, masked, header_length, length) = {
+            let data = &input.get_ref()[pos..];
+            if data.len() < 2 {
+                return Ok(None);
+            }
+            if data[0] & 0x70 != 0 {
+                return Err(Error::new(Kind::Protocol, format!("Reserved bits set in frame header {:#x}.", data[0])));
+            }
+            let opcode = OpCode::from(data[0] & 0x0F);
+            let (length, header_length) = match data[1] & 0x7F {
+                126 => {
+                    if data.len() < 4 {
+                        return Ok(None);
+                    }
+                    (BigEndian::read_u16(&data[2..4]) as u64, 4)
+                }
+                127 => {
+                    if data.len() < 10 {
+                        return Ok(None);
+                    }
+                    (BigEndian::read_u64(&data[2..10]), 10)
+                }
+                length => (length as u64, 2),
+            };
+            let masked = data[1] & 0x80 != 0;
+            (opcode, data[0] & 0x80 != 0, masked, if masked { header_length + 4 } else { header_length }, length)
+        };
+
+        match opcode {
+            OpCode::Bad | OpCode::Extensions => return Err(Error::new(Kind::Protocol, "Encountered invalid opcode.")),
+            _ => (),
+        }
+        if masked == self.client {
+            return Err(Error::new(Kind::Protocol, if self.client { "Received masked frame from server." } else { "Received unmasked frame from client." }));
+        }
+        if opcode.is_control() && length > 125 {
+            return Err(Error::new(Kind::Protocol, format!("Received {} frame with a payload of {} bytes.", opcode, length)));
+        }
+        if length > self.max_frame_length as u64 {
+            return Err(Error::new(Kind::Capacity, format!("Frame length {} exceeds the maximum frame length of {}.", length, self.max_frame_length)));
+        }
+
+        let start = pos + header_length;
+        let end = start + length as usize;
+        if input.get_ref().len() < end {
+            return Ok(None);
+        }
+
+        let mut data = input.get_ref()[start..end].to_vec();
+        if masked {
+            let mask = &input.get_ref()[start - 4..start];
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        input.set_position(end as u64);
+        let mut frame = Frame::new(opcode, data);
+        frame.set_final(finished);
+        Ok(Some(frame))
+    }
+
+    fn encode(&mut self, mut frame: Frame, output: &mut Vec<u8>) -> Result<()> {
+        match frame.opcode() {
+            OpCode::Extensions | OpCode::Bad => {
+                return Err(Error::new(Kind::Internal, format!("Unable to send {} frame over a WebSocket.", frame.opcode())));
+            }
+            OpCode::Close if frame.len() > 125 => {
+                // cut the reason short, at a character boundary
+                let mut end = 125;
+                while end > 2 && from_utf8(&frame.payload()[2..end]).is_err() {
+                    end -= 1;
+                }
+                let payload = frame.payload()[..end].to_vec();
+                frame.set_payload(payload);
+            }
+            opcode if opcode.is_control() && frame.len() > 125 => {
+                return Err(Error::new(Kind::Protocol, format!("Unable to send {} frame with a payload of more than 125 bytes.", opcode)));
+            }
+            _ => (),
+        }
+
+        let mut header: u8 = frame.opcode().into();
+        if frame.is_final() {
+            header |= 0x80;
+        }
+        output.push(header);
+
+        let mask = if self.client { 0x80 } else { 0 };
+        if frame.len() < 126 {
+            output.push(frame.len() as u8 | mask);
+        } else if frame.len() <= u16::MAX as usize {
+            output.push(126 | mask);
+            output.write_u16::<BigEndian>(frame.len() as u16)?;
+        } else {
+            output.push(127 | mask);
+            output.write_u64::<BigEndian>(frame.len() as u64)?;
+        }
+
+        if self.client {
+            let key = rand::random::<[u8; 4]>();
+            output.extend(&key);
+            output.extend(frame.payload().iter().enumerate().map(|(i, byte)| byte ^ key[i % 4]));
+        } else {
+            output.extend(frame.into_data());
+        }
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &Settings) {
+        self.max_frame_length = settings.max_frame_length;
+    }
+
+    fn set_endpoint(&mut self, endpoint: &Endpoint) {
+        self.client = match *endpoint {
+            Endpoint::Client(_) => true,
+            Endpoint::Server => false,
+        };
+    }
+
+    #[inline]
+    fn requires_upgrade(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn supports_control_frames(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn supports_fragmentation(&self) -> bool {
+        true
+    }
+}
+
+/// The encoding of the length in front of every frame of a `LengthPrefixed` codec.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Prefix {
+    /// A fixed four byte big-endian length.
+    U32,
+    /// An unsigned LEB128 varint length of one to ten bytes.
+    Varint,
+}
+
+/// A codec that frames every message with its length.
+///
+/// Incoming frames are delivered as complete binary messages, the type of outgoing messages
+/// is not transmitted. Frames larger than `Settings::max_frame_length` are rejected with a
+/// capacity error.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthPrefixed {
+    prefix: Prefix,
+    max_frame_length: usize,
+}
+
+impl LengthPrefixed {
+    pub fn new(prefix: Prefix) -> LengthPrefixed {
+        LengthPrefixed {
+            prefix,
+            max_frame_length: Settings::default().max_frame_length,
+        }
+    }
+
+    /// Frames prefixed with a four byte big-endian length.
+    pub fn u32() -> LengthPrefixed {
+        LengthPrefixed::new(Prefix::U32)
+    }
+
+    /// Frames prefixed with a varint length.
+    pub fn varint() -> LengthPrefixed {
+        LengthPrefixed::new(Prefix::Varint)
+    }
+
+    pub fn prefix(&self) -> Prefix {
+        self.prefix
+    }
+
+    /// Parse the length prefix at the start of `data`.
+    /// Returns the size of the prefix and the length of the frame that follows it.
+    fn parse_prefix(&self, data: &[u8]) -> Result<Option<(usize, u64)>> {
+        match self.prefix {
+            Prefix::U32 => {
+                if data.len() < 4 {
+                    Ok(None)
+                } else {
+                    Ok(Some((4, u64::from(BigEndian::read_u32(data)))))
+                }
+            }
+            Prefix::Varint => {
+                let mut length = 0u64;
+                for (i, byte) in data.iter().enumerate() {
+                    if i == 10 || (i == 9 && *byte > 1) {
+                        return Err(Error::new(Kind::Protocol, "Varint frame length overflows 64 bits."));
+                    }
+                    length |= u64::from(byte & 0x7F) << (7 * i);
+                    if byte & 0x80 == 0 {
+                        return Ok(Some((i + 1, length)));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Default for LengthPrefixed {
+    fn default() -> LengthPrefixed {
+        LengthPrefixed::u32()
+    }
+}
+
+impl Codec for LengthPrefixed {
+    fn decode(&mut self, input: &mut Cursor<Vec<u8>>) -> Result<Option<Frame>> {
+        let pos = input.position() as usize;
+        let (header, length) = match self.parse_prefix(&input.get_ref()[pos..])? {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+
+        if length > self.max_frame_length as u64 {
+            return Err(Error::new(Kind::Capacity, format!("Frame length {} exceeds the maximum frame length of {}.", length, self.max_frame_length)));
+        }
+
+        let start = pos + header;
+        let end = start + length as usize;
+        if input.get_ref().len() < end {
+            return Ok(None);
+        }
+
+        let data = input.get_ref()[start..end].to_vec();
+        input.set_position(end as u64);
+        Ok(Some(Frame::message(data, OpCode::Binary)))
+    }
+
+    fn encode(&mut self, frame: Frame, output: &mut Vec<u8>) -> Result<()> {
+        let data = frame.into_data();
+        match self.prefix {
+            Prefix::U32 => {
+                if data.len() > u32::MAX as usize {
+                    return Err(Error::new(Kind::Capacity, "Frame is too large for a 32 bit length prefix."));
+                }
+                output.write_u32::<BigEndian>(data.len() as u32)?;
+            }
+            Prefix::Varint => {
+                let mut length = data.len() as u64;
+                while length >= 0x80 {
+                    output.push((length as u8) | 0x80);
+                    length >>= 7;
+                }
+                output.push(length as u8);
+            }
+        }
+        output.extend(data);
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &Settings) {
+        self.max_frame_length = settings.max_frame_length;
+    }
+}
+
+/// A codec for line oriented protocols.
+///
+/// Every complete line is delivered as a text message without its delimiter and outgoing
+/// messages are terminated with the delimiter. A partial line stays in the input buffer until
+/// the rest of it arrives, lines longer than `Settings::max_line_length` are rejected with a
+/// capacity error.
+#[derive(Debug, Clone)]
+pub struct Delimited {
+    delimiter: Vec<u8>,
+    strip_cr: bool,
+    max_line_length: usize,
+    // number of unread bytes already searched for the delimiter
+    searched: usize,
+}
+
+impl Delimited {
+    /// Lines separated by `delimiter`.
+    pub fn new<B>(delimiter: B) -> Delimited
+    where
+        B: Into<Vec<u8>>,
+    {
+        let delimiter = delimiter.into();
+        assert!(!delimiter.is_empty(), "The delimiter of a Delimited codec must not be empty.");
+        Delimited {
+            delimiter,
+            strip_cr: false,
+            max_line_length: Settings::default().max_line_length,
+            searched: 0,
+        }
+    }
+
+    /// Lines terminated by either `\n` or `\r\n`. Outgoing lines are terminated by `\n`.
+    pub fn lines() -> Delimited {
+        let mut codec = Delimited::new("\n");
+        codec.strip_cr = true;
+        codec
+    }
+
+    pub fn delimiter(&self) -> &[u8] {
+        &self.delimiter
+    }
+}
+
+impl Default for Delimited {
+    fn default() -> Delimited {
+        Delimited::lines()
+    }
+}
+
+impl Codec for Delimited {
+    fn decode(&mut self, input: &mut Cursor<Vec<u8>>) -> Result<Option<Frame>> {
+        let pos = input.position() as usize;
+        let (line, consumed) = {
+            let data = &input.get_ref()[pos..];
+            // a delimiter may straddle the bytes searched last time and the new ones
+            let start = self.searched.saturating_sub(self.delimiter.len() - 1);
+            let found = data[start..].windows(self.delimiter.len()).position(|window| window == &self.delimiter[..]);
+
+            match found {
+                Some(index) => {
+                    let end = start + index;
+                    let mut line = &data[..end];
+                    if self.strip_cr && line.last() == Some(&b'\r') {
+                        line = &line[..line.len() - 1];
+                    }
+                    if line.len() > self.max_line_length {
+                        return Err(Error::new(Kind::Capacity, format!("Line length {} exceeds the maximum line length of {}.", line.len(), self.max_line_length)));
+                    }
+                    (line.to_vec(), end + self.delimiter.len())
+                }
+                None => {
+                    if data.len() > self.max_line_length {
+                        return Err(Error::new(Kind::Capacity, format!("Received more than {} bytes without a line delimiter.", self.max_line_length)));
+                    }
+                    self.searched = data.len();
+                    return Ok(None);
+                }
+            }
+        };
+
+        self.searched = 0;
+        input.set_position((pos + consumed) as u64);
+        Ok(Some(Frame::message(line, OpCode::Text)))
+    }
+
+    fn encode(&mut self, frame: Frame, output: &mut Vec<u8>) -> Result<()> {
+        output.extend(frame.into_data());
+        output.extend(&self.delimiter);
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &Settings) {
+        self.max_line_length = settings.max_line_length;
+    }
+}
+
+/// Build a `NewCodec` that hands out clones of `codec`.
+pub fn new_codec<C>(codec: C) -> NewCodec
+where
+    C: Codec + Clone + Send + Sync + 'static,
+{
+    Arc::new(move || Box::new(codec.clone()))
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use message::Message;
+    use protocol::CloseCode;
+
+    fn text(data: &str) -> Frame {
+        Frame::message(data.into(), OpCode::Text)
+    }
+
+    fn binary(data: Vec<u8>) -> Frame {
+        Frame::message(data, OpCode::Binary)
+    }
+
+    #[test]
+    fn raw_decode() {
+        let mut raw = Raw::default();
+        let mut input = Cursor::new(b"hello".to_vec());
+        assert_eq!(raw.decode(&mut input).unwrap(), Some(text("hello")));
+        assert_eq!(input.position(), 5);
+        assert_eq!(raw.decode(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn raw_encode() {
+        let mut raw = Raw::default();
+        let mut output = Vec::new();
+        raw.encode(Frame::from(Message::text("hello")), &mut output).unwrap();
+        raw.encode(Frame::from(Message::binary(vec![1, 2])), &mut output).unwrap();
+        assert_eq!(output, b"hello\x01\x02".to_vec());
+    }
+
+    #[test]
+    fn raw_opcode() {
+        let mut settings = Settings::default();
+        settings.raw_opcode = OpCode::Binary;
+        let mut raw = Raw::default();
+        raw.configure(&settings);
+        let mut input = Cursor::new(b"hello".to_vec());
+        assert_eq!(raw.decode(&mut input).unwrap(), Some(binary(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn framed() {
+        let mut codec = Framed::default();
+        let mut output = Vec::new();
+        codec.encode(Frame::from(Message::text("hi")), &mut output).unwrap();
+        codec.encode(Frame::from(Message::binary(vec![0xFF])), &mut output).unwrap();
+        assert_eq!(output, vec![1, 0, 0, 0, 2, b'h', b'i', 2, 0, 0, 0, 1, 0xFF]);
+
+        let mut input = Cursor::new(output[..9].to_vec());
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(text("hi")));
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+        assert_eq!(input.position(), 7);
+
+        input.get_mut().extend(&output[9..]);
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(binary(vec![0xFF])));
+    }
+
+    #[test]
+    fn framed_close() {
+        let mut codec = Framed::default();
+        let mut output = Vec::new();
+        codec.encode(Frame::close(CloseCode::Normal, ""), &mut output).unwrap();
+        assert_eq!(output, vec![8, 0, 0, 0, 2, 0x03, 0xE8]);
+
+        let mut input = Cursor::new(output);
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(Frame::close(CloseCode::Normal, "")));
+    }
+
+    #[test]
+    fn framed_compressed() {
+        let mut codec = Framed::default();
+        let mut frame = binary(vec![1, 2]);
+        frame.set_compressed(true);
+        let mut output = Vec::new();
+        codec.encode(frame.clone(), &mut output).unwrap();
+        assert_eq!(output, vec![0x42, 0, 0, 0, 2, 1, 2]);
+
+        let mut input = Cursor::new(output);
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(frame));
+    }
+
+    #[test]
+    fn framed_fragments() {
+        let mut codec = Framed::default();
+        let mut first = text("a");
+        first.set_final(false);
+        let last = Frame::new(OpCode::Continue, b"b".to_vec());
+        let mut output = Vec::new();
+        codec.encode(first.clone(), &mut output).unwrap();
+        codec.encode(last.clone(), &mut output).unwrap();
+        assert_eq!(output, vec![0x21, 0, 0, 0, 1, b'a', 0x00, 0, 0, 0, 1, b'b']);
+
+        let mut input = Cursor::new(output);
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(first));
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(last));
+    }
+
+    #[test]
+    fn framed_invalid_header() {
+        let mut codec = Framed::default();
+        let mut reserved = Cursor::new(vec![0x81, 0, 0, 0, 0]);
+        assert!(codec.decode(&mut reserved).is_err());
+        let mut bad = Cursor::new(vec![0x0F, 0, 0, 0, 0]);
+        assert!(codec.decode(&mut bad).is_err());
+    }
+
+    #[test]
+    fn websocket_masking() {
+        let mut client = WebSocket::default();
+        client.set_endpoint(&Endpoint::Client("ws://127.0.0.1:3012".into()));
+        let mut server = WebSocket::default();
+
+        let mut output = Vec::new();
+        client.encode(text("Hello"), &mut output).unwrap();
+        assert_eq!(&output[..2], &[0x81, 0x85]);
+        assert_ne!(&output[6..], b"Hello");
+
+        let mut input = Cursor::new(output.clone());
+        assert_eq!(server.decode(&mut input).unwrap(), Some(text("Hello")));
+        // servers never accept unmasked frames and clients never accept masked ones
+        assert!(client.decode(&mut Cursor::new(output)).is_err());
+        assert!(server.decode(&mut Cursor::new(vec![0x81, 0x01, b'a'])).is_err());
+    }
+
+    #[test]
+    fn websocket_rfc_examples() {
+        let mut client = WebSocket::default();
+        client.set_endpoint(&Endpoint::Client("ws://127.0.0.1:3012".into()));
+        let mut server = WebSocket::default();
+
+        // unmasked text message from RFC 6455 section 5.7
+        let mut input = Cursor::new(vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+        assert_eq!(client.decode(&mut input).unwrap(), Some(text("Hello")));
+        let mut output = Vec::new();
+        server.encode(text("Hello"), &mut output).unwrap();
+        assert_eq!(output, input.into_inner());
+
+        // masked text message
+        let mut input = Cursor::new(vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+        assert_eq!(server.decode(&mut input).unwrap(), Some(text("Hello")));
+
+        // fragmented text message
+        let mut input = Cursor::new(vec![0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f]);
+        let first = client.decode(&mut input).unwrap().unwrap();
+        assert!(!first.is_final());
+        assert_eq!(first.opcode(), OpCode::Text);
+        let last = client.decode(&mut input).unwrap().unwrap();
+        assert!(last.is_final());
+        assert_eq!(last.opcode(), OpCode::Continue);
+
+        // 256 bytes binary message with a 16 bit length
+        let mut output = Vec::new();
+        server.encode(binary(vec![0; 256]), &mut output).unwrap();
+        assert_eq!(&output[..4], &[0x82, 0x7E, 0x01, 0x00]);
+        assert_eq!(client.decode(&mut Cursor::new(output[..100].to_vec())).unwrap(), None);
+        assert_eq!(client.decode(&mut Cursor::new(output)).unwrap(), Some(binary(vec![0; 256])));
+    }
+
+    #[test]
+    fn websocket_control_frames() {
+        let mut server = WebSocket::default();
+        let mut output = Vec::new();
+        let reason: String = ::std::iter::repeat('\u{e9}').take(100).collect();
+        server.encode(Frame::close(CloseCode::Error, &reason), &mut output).unwrap();
+        assert_eq!(output[1], 125 - 1);
+        assert!(server.encode(Frame::ping(vec![0; 126]), &mut output).is_err());
+        assert!(server.encode(Frame::extensions(&["permessage-deflate"]), &mut output).is_err());
+    }
+
+    #[test]
+    fn length_prefixed_u32() {
+        let mut codec = LengthPrefixed::u32();
+        let mut output = Vec::new();
+        codec.encode(Frame::from(Message::binary(vec![1, 2, 3])), &mut output).unwrap();
+        assert_eq!(output, vec![0, 0, 0, 3, 1, 2, 3]);
+
+        let mut input = Cursor::new(output[..5].to_vec());
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+        assert_eq!(input.position(), 0);
+
+        input.get_mut().extend(&output[5..]);
+        input.get_mut().extend(&output[..]);
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(binary(vec![1, 2, 3])));
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(binary(vec![1, 2, 3])));
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+        assert_eq!(input.position(), 14);
+    }
+
+    #[test]
+    fn length_prefixed_varint() {
+        let mut codec = LengthPrefixed::varint();
+        let mut output = Vec::new();
+        codec.encode(Frame::from(Message::text("x".repeat(300))), &mut output).unwrap();
+        assert_eq!(&output[..2], &[0xAC, 0x02]);
+        assert_eq!(output.len(), 302);
+
+        let mut input = Cursor::new(output);
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(binary("x".repeat(300).into_bytes())));
+
+        let mut overflow = Cursor::new(vec![0xFF; 11]);
+        assert!(codec.decode(&mut overflow).is_err());
+    }
+
+    #[test]
+    fn length_prefixed_max_frame_length() {
+        let mut settings = Settings::default();
+        settings.max_frame_length = 2;
+        let mut codec = LengthPrefixed::u32();
+        codec.configure(&settings);
+
+        let mut input = Cursor::new(vec![0, 0, 0, 3, 1, 2, 3]);
+        match codec.decode(&mut input) {
+            Err(Error { kind: Kind::Capacity, .. }) => (),
+            other => panic!("Expected capacity error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delimited_lines() {
+        let mut codec = Delimited::lines();
+        let mut input = Cursor::new(b"one\r\ntwo\nthr".to_vec());
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(text("one")));
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(text("two")));
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+        assert_eq!(input.position(), 9);
+
+        input.get_mut().extend(b"ee\n");
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(text("three")));
+
+        let mut output = Vec::new();
+        codec.encode(Frame::from(Message::text("four")), &mut output).unwrap();
+        assert_eq!(output, b"four\n".to_vec());
+    }
+
+    #[test]
+    fn delimited_split_delimiter() {
+        let mut codec = Delimited::new("||");
+        let mut input = Cursor::new(b"a|".to_vec());
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+        input.get_mut().extend(b"|b");
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(text("a")));
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn delimited_max_line_length() {
+        let mut settings = Settings::default();
+        settings.max_line_length = 4;
+        let mut codec = Delimited::lines();
+        codec.configure(&settings);
+
+        let mut input = Cursor::new(b"abcd\nabcde".to_vec());
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(text("abcd")));
+        match codec.decode(&mut input) {
+            Err(Error { kind: Kind::Capacity, .. }) => (),
+            other => panic!("Expected capacity error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn new_codec_clones() {
+        let new = new_codec(Raw::default());
+        let mut first = new();
+        let mut second = new();
+        let mut output = Vec::new();
+        first.encode(Frame::from(Message::text("a")), &mut output).unwrap();
+        second.encode(Frame::from(Message::text("b")), &mut output).unwrap();
+        assert_eq!(output, b"ab".to_vec());
+    }
+}
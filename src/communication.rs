@@ -3,7 +3,7 @@ use message;
 use mio;
 use mio::Token;
 use protocol::CloseCode;
-use result::{Result, Error};
+use result::{Result, Error, Kind};
 use std::borrow::Cow;
 use std::convert::Into;
 use std::net::{SocketAddr, ToSocketAddrs};
@@ -14,8 +14,13 @@ use url;
 pub enum Signal {
     Message(message::Message),
     Close(CloseCode, Cow<'static, str>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
     Connect(String),
+    Listen(SocketAddr),
+    StopListening(SocketAddr),
     Shutdown,
+    GracefulShutdown(u64),
     Timeout { delay: u64, token: Token },
     Cancel(mio::timer::Timeout),
 }
@@ -63,6 +68,12 @@ impl Sender {
         self.token
     }
 
+    /// The id of the connection this sender talks to.
+    #[inline]
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
 
     pub fn send<M>(&self, msg: M) -> Result<()>
     where
@@ -78,6 +89,30 @@ impl Sender {
     }
 
 
+    // Queue a signal without waiting for room in the queue.
+    pub(crate) fn try_signal(&self, signal: Signal) -> ::std::result::Result<(), mio::channel::TrySendError<Command>> {
+        self.channel.try_send(Command {
+            token: self.token,
+            signal,
+            connection_id: self.connection_id,
+        })
+    }
+
+    // Queue a message without waiting for room in the queue, a full queue hands it back.
+    #[cfg(feature = "async")]
+    pub(crate) fn try_send(&self, msg: message::Message) -> Result<Option<message::Message>> {
+        match self.try_signal(Signal::Message(msg)) {
+            Ok(()) => Ok(None),
+            Err(mio::channel::TrySendError::Full(cmd)) => match cmd.signal {
+                Signal::Message(msg) => Ok(Some(msg)),
+                _ => unreachable!("A queued message came back as another signal."),
+            },
+            Err(mio::channel::TrySendError::Disconnected(cmd)) => Err(Error::from(mio::channel::SendError::Disconnected(cmd))),
+            Err(mio::channel::TrySendError::Io(err)) => Err(Error::from(mio::channel::SendError::Io(err))),
+        }
+    }
+
+
     pub fn broadcast<M>(&self, msg: M) -> Result<()>
     where
         M: Into<message::Message>,
@@ -118,7 +153,33 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Send a ping to the other endpoint, its answer is passed to `Handler::on_pong`.
+    #[inline]
+    pub fn ping(&self, data: Vec<u8>) -> Result<()> {
+        self.channel
+            .send(Command {
+                      token: self.token,
+                      signal: Signal::Ping(data),
+                      connection_id: self.connection_id,
+                  })
+            .map_err(Error::from)
+    }
+
+    /// Send an unsolicited pong to the other endpoint.
+    #[inline]
+    pub fn pong(&self, data: Vec<u8>) -> Result<()> {
+        self.channel
+            .send(Command {
+                      token: self.token,
+                      signal: Signal::Pong(data),
+                      connection_id: self.connection_id,
+                  })
+            .map_err(Error::from)
+    }
 
+    /// Queue a connection to `url`, a `tcp://`, `ws://` or `wss://` url or a plain `host:port`.
+    /// Unix domain sockets are not supported, `unix://` urls fail like unresolvable hosts
+    /// through `Handler::on_connect_failed`.
     #[inline]
     pub fn connect(&self, url: String) -> Result<()> {
         self.channel
@@ -131,6 +192,35 @@ impl Sender {
     }
 
 
+    /// Start accepting connections on the first address `addr_spec` resolves to, next to the
+    /// addresses the event loop already listens on.
+    #[inline]
+    pub fn listen<A>(&self, addr_spec: A) -> Result<()>
+    where
+        A: ToSocketAddrs,
+    {
+        let addr = addr_spec.to_socket_addrs()?.next().ok_or_else(|| Error::new(Kind::Internal, "No address given"))?;
+        self.channel
+            .send(Command {
+                      token: self.token,
+                      signal: Signal::Listen(addr),
+                      connection_id: self.connection_id,
+                  })
+            .map_err(Error::from)
+    }
+
+    /// Stop accepting connections on a listening address, connections it accepted stay open.
+    #[inline]
+    pub fn stop_listening(&self, addr: SocketAddr) -> Result<()> {
+        self.channel
+            .send(Command {
+                      token: self.token,
+                      signal: Signal::StopListening(addr),
+                      connection_id: self.connection_id,
+                  })
+            .map_err(Error::from)
+    }
+
     #[inline]
     pub fn shutdown(&self) -> Result<()> {
         self.channel
@@ -142,6 +232,21 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Shut down once every connection has flushed what it has left and closed with
+    /// `CloseCode::Away`, or after `deadline` milliseconds. New connections are no longer
+    /// accepted or made. Connections still open at the deadline are dropped and listed by
+    /// `XnetSocket::cut_off`.
+    #[inline]
+    pub fn shutdown_gracefully(&self, deadline: u64) -> Result<()> {
+        self.channel
+            .send(Command {
+                      token: self.token,
+                      signal: Signal::GracefulShutdown(deadline),
+                      connection_id: self.connection_id,
+                  })
+            .map_err(Error::from)
+    }
+
     /// Schedule a `token` to be sent to the Socket Handler's `on_timeout` method
     /// after `ms` milliseconds
     #[inline]
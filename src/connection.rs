@@ -3,23 +3,55 @@ use self::Endpoint::*;
 use self::State::*;
 
 use super::Settings;
+use codec::Codec;
+#[cfg(feature = "permessage-deflate")]
+use deflate::{self, Compressor, Decompressor};
+use frame::Frame;
 use handler::Handler;
+use handshake::{Handshake, Request, Response};
 
 use message::Message;
 use mio::{Token, Ready};
 use mio::tcp::TcpStream;
 use mio::timer::Timeout;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{SslAcceptor, SslConnector};
 use protocol::{CloseCode, OpCode};
 use result::{Result, Error, Kind};
 use std::borrow::Borrow;
 use std::collections::VecDeque;
-use std::io::{Write, Read, Cursor, Seek, SeekFrom};
-use std::mem::replace;
-use std::net::SocketAddr;
+use std::io::{self, Write, Cursor};
+use std::mem;
+use std::net::{SocketAddr, Shutdown};
 use std::str::from_utf8;
+use std::time::SystemTime;
 use stream::{Stream, TryReadBuf, TryWriteBuf};
 
-use url;
+use url::Url;
+
+/// The largest HTTP request or response accepted while upgrading to a WebSocket.
+const MAX_HANDSHAKE: usize = 16 * 1024;
+
+/// The timers a connection runs for itself. They are kept apart from the tokens of
+/// `Sender::timeout`, so every token a handler schedules comes back to `Handler::on_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// Give up on a close handshake that the other endpoint never answers.
+    Close,
+    /// Ping the other endpoint and notice when it stopped answering.
+    Keepalive,
+    /// Give up on an outbound connection that does not open in time.
+    Connect,
+}
+
+/// A change to the timers of a connection, carried out by the event loop on its own timer.
+#[derive(Debug)]
+pub enum TimerRequest {
+    /// Fire `Timer` after the given number of milliseconds.
+    Arm(Timer, u64),
+    /// Drop a timer that was armed before.
+    Cancel(Timeout),
+}
 
 #[derive(Debug)]
 pub enum State {
@@ -27,8 +59,11 @@ pub enum State {
     Connecting(Cursor<Vec<u8>>, Cursor<Vec<u8>>),
     // Ready to send/receive messages
     Open,
+    // We sent a close and wait for the other endpoint to answer it
     AwaitingClose,
+    // The other endpoint sent a close that we are about to answer
     RespondingClose,
+    // Both endpoints have sent their close, the socket goes away once the buffer is flushed
     FinishedClose,
 }
 
@@ -67,6 +102,11 @@ impl State {
             _ => false,
         }
     }
+
+    #[inline]
+    pub fn is_closed_by_peer(&self) -> bool {
+        matches!(*self, State::RespondingClose | State::FinishedClose)
+    }
 }
 
 pub struct Connection<H>
@@ -90,13 +130,46 @@ where
     settings: Settings,
     //连接id,可能会出现同一个socket，不同id的情况。
     connection_id: u32,
+    //消息的分帧方式
+    codec: Box<dyn Codec + Send>,
+    //等待事件循环设置或取消的定时器
+    timers: Vec<TimerRequest>,
+    close_timeout: Option<Timeout>,
+    keepalive_timeout: Option<Timeout>,
+    connect_timeout: Option<Timeout>,
+    //连续没有收到对端数据的心跳周期数
+    keepalive_missed: u32,
+    //WebSocket的HTTP握手是否正在进行
+    upgrading: bool,
+    //客户端连接的重连次数，以及是否是主动关闭的
+    attempt: u32,
+    close_requested: bool,
+    request: Option<Request>,
+    response: Option<Response>,
+    //收到的分片，等待最后一片后合并成一条消息
+    fragments_in: VecDeque<Frame>,
+    //已收到分片的总长度
+    fragments_size: usize,
+    //超出分片数量限制的消息，丢弃其余的分片
+    skip_fragments: bool,
+    //等待发送的分片，控制帧可以插在分片之间发送
+    fragments_out: VecDeque<Frame>,
+    //客户端的TLS配置，重连时使用
+    #[cfg(feature = "ssl")]
+    tls_client: Option<(SslConnector, String)>,
+    //对端支持压缩后才压缩发出的消息
+    #[cfg(feature = "permessage-deflate")]
+    compressor: Option<Compressor>,
+    #[cfg(feature = "permessage-deflate")]
+    decompressor: Option<Decompressor>,
 }
 
 impl<H> Connection<H>
 where
     H: Handler,
 {
-    pub fn new(tok: Token, sock: TcpStream, handler: H, settings: Settings, connection_id: u32) -> Connection<H> {
+    pub fn new(tok: Token, sock: TcpStream, handler: H, settings: Settings, connection_id: u32, mut codec: Box<dyn Codec + Send>) -> Connection<H> {
+        codec.configure(&settings);
         Connection {
             token: tok,
             socket: Stream::tcp(sock),
@@ -109,15 +182,53 @@ where
             addresses: Vec::new(),
             settings: settings,
             connection_id: connection_id,
+            codec,
+            timers: Vec::new(),
+            close_timeout: None,
+            keepalive_timeout: None,
+            connect_timeout: None,
+            keepalive_missed: 0,
+            upgrading: false,
+            attempt: 0,
+            close_requested: false,
+            request: None,
+            response: None,
+            fragments_in: VecDeque::with_capacity(settings.fragments_capacity),
+            fragments_size: 0,
+            skip_fragments: false,
+            fragments_out: VecDeque::new(),
+            #[cfg(feature = "ssl")]
+            tls_client: None,
+            #[cfg(feature = "permessage-deflate")]
+            compressor: None,
+            #[cfg(feature = "permessage-deflate")]
+            decompressor: None,
         }
     }
 
     //socket successed callback the function
     pub fn open(&mut self) -> Result<()> {
         trace!("accept socket{:?}", self.token);
-        if let Connecting(ref req, ref res) = replace(&mut self.state, Open) {
-            trace!("accept new socket change state connecting  to open {}", self.peer_addr());
-            return Ok(());
+        if self.state.is_connecting() {
+            if self.codec.requires_upgrade() && self.response.is_none() {
+                return self.start_upgrade();
+            }
+            let shake = Handshake {
+                peer_addr: self.socket.peer_addr()?,
+                local_addr: self.socket.local_addr()?,
+                endpoint: self.endpoint.clone(),
+                connection_id: self.connection_id,
+                accepted_at: SystemTime::now(),
+                request: self.request.take(),
+                response: self.response.take(),
+            };
+            self.state = Open;
+            self.cancel_connect_timeout();
+            trace!("accept new socket change state connecting  to open {}", shake.peer_addr);
+            self.send_extensions()?;
+            self.schedule_keepalive()?;
+            self.check_events();
+            self.handler.on_open(shake)
         } else {
             Err(Error::new(Kind::Internal, "Tried to write socket while not in connecting state!"))
         }
@@ -125,16 +236,22 @@ where
 
     pub fn as_server(&mut self) -> Result<()> {
         trace!("new server socket half ");
+        self.codec.set_endpoint(&self.endpoint);
         Ok(self.events.insert(Ready::readable()))
     }
 
     pub fn as_client(&mut self, url: String, addrs: Vec<SocketAddr>) -> Result<()> {
         trace!("new client socket half ");
         match self.state {
-            State::Open => {
+            State::Connecting(..) => {
                 self.addresses = addrs;
-                self.events.insert(Ready::readable()); //监听可读
+                // the socket becomes writable once the non-blocking connect finished
+                self.events.insert(Ready::writable());
                 self.endpoint = Endpoint::Client(url);
+                self.codec.set_endpoint(&self.endpoint);
+                if self.settings.connect_timeout > 0 {
+                    self.timers.push(TimerRequest::Arm(Timer::Connect, self.settings.connect_timeout));
+                }
                 Ok(())
             }
 
@@ -144,6 +261,31 @@ where
         }
     }
 
+    /// Negotiate TLS on an accepted connection, it is opened once the handshake finished.
+    #[cfg(feature = "ssl")]
+    pub fn as_tls_server(&mut self, acceptor: &SslAcceptor) -> Result<()> {
+        trace!("Starting TLS negotiation as server.");
+        let sock = self.socket.take_tcp()?;
+        self.socket = Stream::tls_server(acceptor, sock)?;
+        self.check_negotiating();
+        Ok(())
+    }
+
+    /// Negotiate TLS on an outbound connection, also for sockets of later connect attempts.
+    #[cfg(feature = "ssl")]
+    pub fn as_tls_client(&mut self, connector: SslConnector, domain: String) -> Result<()> {
+        trace!("Starting TLS negotiation with {}.", domain);
+        let sock = self.socket.take_tcp()?;
+        self.socket = Stream::tls_client(&connector, &domain, sock)?;
+        self.tls_client = Some((connector, domain));
+        self.check_negotiating();
+        Ok(())
+    }
+
+    pub fn is_negotiating(&self) -> bool {
+        self.socket.is_negotiating()
+    }
+
     pub fn token(&self) -> Token {
         self.token
     }
@@ -156,6 +298,10 @@ where
         self.connection_id
     }
 
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
     fn peer_addr(&self) -> String {
         if let Ok(addr) = self.socket.peer_addr() { addr.to_string() } else { "UNKNOWN".into() }
     }
@@ -167,12 +313,24 @@ where
         // socket is client
         if self.is_client() {
             if let Connecting(ref mut req, ref mut res) = self.state {
+                req.get_mut().clear();
                 req.set_position(0);
+                res.get_mut().clear();
                 res.set_position(0);
-                self.events.remove(Ready::writable());
-                self.events.insert(Ready::readable());
+                // the next socket starts its own WebSocket handshake
+                self.upgrading = false;
+                self.request = None;
+                self.events = Ready::writable();
                 if let Some(ref addr) = self.addresses.pop() {
                     let sock = try!(TcpStream::connect(addr));
+                    #[cfg(feature = "ssl")]
+                    {
+                        if let Some((ref connector, ref domain)) = self.tls_client {
+                            self.socket = Stream::tls_client(connector, domain, sock)?;
+                            self.check_negotiating();
+                            return Ok(());
+                        }
+                    }
                     Ok(self.socket = Stream::tcp(sock))
                 } else {
                     if self.settings.panic_on_new_connection {
@@ -199,6 +357,7 @@ where
         }
     }
 
+    #[allow(dead_code)]
     pub fn is_server(&self) -> bool {
         match self.endpoint {
             Client(_) => false,
@@ -206,8 +365,40 @@ where
         }
     }
 
+    /// Record which reconnect attempt made this connection.
+    pub fn set_attempt(&mut self, attempt: u32) {
+        self.attempt = attempt;
+    }
+
+    /// The url to dial again once this connection is lost along with the number of that attempt.
+    /// `None` for server connections and connections closed on request.
+    pub fn redial(&self) -> Option<(String, u32)> {
+        match self.endpoint {
+            Client(ref url) if !self.close_requested => {
+                // a connection that made it open starts counting again
+                let attempt = if self.state.is_connecting() { self.attempt + 1 } else { 1 };
+                Some((url.clone(), attempt))
+            }
+            _ => None,
+        }
+    }
+
+    /// Close the connection as asked for through a `Sender`, it is not dialed again.
+    pub fn request_close<R>(&mut self, code: CloseCode, reason: R) -> Result<()>
+    where
+        R: Borrow<str>,
+    {
+        self.close_requested = true;
+        self.send_close(code, reason)
+    }
+
     pub fn shutdown(&mut self) {
+        self.close_requested = true;
         self.handler.on_shutdown();
+        if self.state.is_connecting() {
+            // nothing was said yet, so there is nothing to close
+            return self.disconnect();
+        }
         if let Err(err) = self.send_close(CloseCode::Away, "Shutting down.") {
             self.handler.on_error(err);
             self.disconnect()
@@ -224,16 +415,77 @@ where
         self.handler.on_timeout(event)
     }
 
+    /// The timer requests made since the last call, the event loop carries them out.
+    pub fn take_timers(&mut self) -> Vec<TimerRequest> {
+        mem::take(&mut self.timers)
+    }
+
+    pub fn new_timer(&mut self, timer: Timer, timeout: Timeout) {
+        let armed = match timer {
+            Timer::Keepalive => if let Open = self.state { Some(&mut self.keepalive_timeout) } else { None },
+            Timer::Connect => {
+                if self.state.is_connecting() && !self.events.is_empty() {
+                    Some(&mut self.connect_timeout)
+                } else {
+                    None
+                }
+            }
+            Timer::Close => if let AwaitingClose = self.state { Some(&mut self.close_timeout) } else { None },
+        };
+        match armed {
+            Some(slot) => *slot = Some(timeout),
+            // the state moved on before the timer was armed
+            None => self.timers.push(TimerRequest::Cancel(timeout)),
+        }
+    }
+
+    pub fn timer_triggered(&mut self, timer: Timer) -> Result<()> {
+        match timer {
+            Timer::Keepalive => {
+                self.keepalive_timeout = None;
+                self.keepalive()
+            }
+            Timer::Close => {
+                self.close_timeout = None;
+                if let AwaitingClose = self.state {
+                    debug!("Close handshake with {} timed out.", self.peer_addr());
+                    self.disconnect();
+                }
+                Ok(())
+            }
+            Timer::Connect => {
+                self.connect_timeout = None;
+                if self.state.is_connecting() {
+                    debug!("Connecting to {:?} timed out.", self.endpoint);
+                    let err = io::Error::new(io::ErrorKind::TimedOut, "Timed out before the connection opened.");
+                    self.error(Error::from(err));
+                }
+                Ok(())
+            }
+        }
+    }
+
     pub fn error(&mut self, err: Error) {
+        // only a server in the middle of the HTTP upgrade can still answer with a status
+        let client = self.is_client();
+        let reply = self.upgrading && !client;
         match self.state {
+            Connecting(..) if client => {
+                if let Client(ref url) = self.endpoint {
+                    self.handler.on_connect_failed(url, err);
+                }
+                self.disconnect();
+            }
             Connecting(_, ref mut res) => {
                 match err.kind {
                     Kind::Protocol => {
                         let msg = err.to_string();
                         self.handler.on_error(err);
-                        if let Server = self.endpoint {
+                        if reply {
+                            self.upgrading = false;
                             res.get_mut().clear();
-                            if let Err(err) = write!(res.get_mut(), "Bad Request\r\n\r\n{}", msg) {
+                            res.set_position(0);
+                            if let Err(err) = write!(res.get_mut(), "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n{}", msg) {
                                 self.handler.on_error(Error::from(err));
                                 self.events = Ready::empty();
                             } else {
@@ -247,9 +499,11 @@ where
                     _ => {
                         let msg = err.to_string();
                         self.handler.on_error(err);
-                        if let Server = self.endpoint {
+                        if reply {
+                            self.upgrading = false;
                             res.get_mut().clear();
-                            if let Err(err) = write!(res.get_mut(), "Internal Server Error\r\n\r\n{}", msg) {
+                            res.set_position(0);
+                            if let Err(err) = write!(res.get_mut(), "HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\n\r\n{}", msg) {
                                 self.handler.on_error(Error::from(err));
                                 self.events = Ready::empty();
                             } else {
@@ -352,9 +606,65 @@ where
                 self.handler.on_close(CloseCode::Abnormal, "");
             }
         }
+        self.cancel_close_timeout();
+        self.cancel_keepalive();
+        self.cancel_connect_timeout();
         self.events = Ready::empty()
     }
 
+    fn schedule_keepalive(&mut self) -> Result<()> {
+        if self.settings.keepalive_interval > 0 && self.codec.supports_control_frames() {
+            self.timers.push(TimerRequest::Arm(Timer::Keepalive, self.settings.keepalive_interval));
+        }
+        Ok(())
+    }
+
+    // Ping the other endpoint, or give up on it once it missed too many intervals.
+    fn keepalive(&mut self) -> Result<()> {
+        if let Open = self.state {
+            if self.keepalive_missed >= self.settings.keepalive_misses {
+                debug!("Connection to {} missed {} keepalive intervals.", self.peer_addr(), self.keepalive_missed);
+                // Tell the other endpoint in case it is only slow, but do not wait for an answer
+                // from an endpoint that stopped answering pings. A single write that does not
+                // block is all it gets.
+                if self.codec.supports_control_frames() {
+                    let frame = Frame::close(CloseCode::Away, "Keepalive timed out.");
+                    if self.check_buffer_out(frame.len()).is_ok() && self.codec.encode(frame, self.out_buffer.get_mut()).is_ok() {
+                        if let Err(err) = self.socket.try_write_buf(&mut self.out_buffer) {
+                            trace!("Unable to send close to {}: {}", self.peer_addr(), err);
+                        }
+                    }
+                }
+                self.state = FinishedClose;
+                self.handler.on_close(CloseCode::Away, "Keepalive timed out.");
+                self.disconnect();
+                return Ok(());
+            }
+            self.keepalive_missed += 1;
+            self.send_ping(Vec::new())?;
+            self.schedule_keepalive()?;
+        }
+        Ok(())
+    }
+
+    fn cancel_keepalive(&mut self) {
+        if let Some(timeout) = self.keepalive_timeout.take() {
+            self.timers.push(TimerRequest::Cancel(timeout));
+        }
+    }
+
+    fn cancel_connect_timeout(&mut self) {
+        if let Some(timeout) = self.connect_timeout.take() {
+            self.timers.push(TimerRequest::Cancel(timeout));
+        }
+    }
+
+    fn cancel_close_timeout(&mut self) {
+        if let Some(timeout) = self.close_timeout.take() {
+            self.timers.push(TimerRequest::Cancel(timeout));
+        }
+    }
+
     ///
     pub fn consume(self) -> H {
         self.handler
@@ -363,26 +673,27 @@ where
 
     pub fn read(&mut self) -> Result<()> {
         if self.socket.is_negotiating() {
-            trace!("Performing TLS negotiation on {}.", self.peer_addr());
-            self.socket.clear_negotiating()?;
-            Ok(())
+            self.negotiate()
         } else {
             if self.state.is_connecting() {
+                if self.upgrading {
+                    return self.read_handshake();
+                }
                 trace!("connect state not change {}.", self.peer_addr());
                 Err(Error::new(Kind::Internal, "connect state not change"))
             } else {
                 trace!("Ready to read messages from {}.", self.peer_addr());
-                //TODO
                 if let Some(len) = self.buffer_in()? {
                     trace!("read data {}", len);
-                    //read data in in_buffer
-                    self.read_data()?;
                     if len == 0 {
-                        if self.events.is_writable() {
-                            self.events.remove(Ready::readable());
-                        } else {
-                            self.disconnect()
+                        // the other endpoint may have sent data right before closing
+                        if self.in_buffer.position() < self.in_buffer.get_ref().len() as u64 {
+                            self.read_data()?;
                         }
+                        self.read_eof()?;
+                    } else {
+                        //read data in in_buffer
+                        self.read_data()?;
                     }
                 }
                 Ok(())
@@ -390,27 +701,255 @@ where
         }
     }
 
+    fn read_eof(&mut self) -> Result<()> {
+        trace!("Reached end of stream from {}.", self.peer_addr());
+        self.events.remove(Ready::readable());
+        if !self.codec.supports_control_frames() {
+            // Without close frames the end of the stream is how the other endpoint closes.
+            match self.state {
+                Open => {
+                    self.state = RespondingClose;
+                    self.handler.on_close(CloseCode::Status, "");
+                    return self.send_close(CloseCode::Empty, "");
+                }
+                AwaitingClose => {
+                    self.state = FinishedClose;
+                    self.handler.on_close(CloseCode::Status, "");
+                    self.check_close();
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+
+        if !self.events.is_writable() {
+            self.disconnect()
+        }
+        Ok(())
+    }
+
     fn read_data(&mut self) -> Result<()> {
-        //读取数据。
-        let mut buffer = Vec::with_capacity(self.in_buffer.get_ref().len());
-        match self.in_buffer.read_to_end(&mut buffer) {
-            Ok(data_size) => {
-                let msg = Message::text((String::from_utf8(buffer).map_err(|err| err.utf8_error()))?);
-                self.handler.on_message(msg)?;
+        // anything from the other endpoint shows that it is still there
+        self.keepalive_missed = 0;
+        //按照codec分帧读取数据。
+        let res = self.decode_messages();
+
+        // Drop the consumed bytes, a partial message stays in the buffer until the rest arrives
+        let pos = self.in_buffer.position() as usize;
+        if pos > 0 {
+            self.in_buffer.get_mut().drain(..pos);
+            self.in_buffer.set_position(0);
+        }
+        res
+    }
+
+    fn decode_messages(&mut self) -> Result<()> {
+        while let Some(frame) = self.codec.decode(&mut self.in_buffer)? {
+            trace!("Decoded frame from {}: {}", self.peer_addr(), frame);
+            self.handle_frame(frame)?;
+        }
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<()> {
+        if frame.is_control() {
+            return self.handle_control(frame);
+        }
+
+        let frame = match self.reassemble(frame)? {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let frame = if frame.is_compressed() { self.inflate(frame)? } else { frame };
+
+        if self.state.is_closed_by_peer() {
+            trace!("Ignoring {} received from {} after its close.", frame, self.peer_addr());
+            return Ok(());
+        }
+        match frame.opcode() {
+            OpCode::Text => {
+                let text = String::from_utf8(frame.into_data()).map_err(|err| err.utf8_error())?;
+                self.handler.on_message(Message::Text(text))
+            }
+            OpCode::Binary => self.handler.on_message(Message::Binary(frame.into_data())),
+            opcode => Err(Error::new(Kind::Protocol, format!("Received unexpected {} frame.", opcode))),
+        }
+    }
+
+    fn handle_control(&mut self, frame: Frame) -> Result<()> {
+        if !frame.is_final() {
+            return Err(Error::new(Kind::Protocol, format!("Received fragmented {} frame.", frame.opcode())));
+        }
+        if frame.is_compressed() {
+            return Err(Error::new(Kind::Protocol, format!("Received compressed {} frame.", frame.opcode())));
+        }
+        match frame.opcode() {
+            OpCode::Close => self.handle_close(frame),
+            OpCode::Ping => {
+                trace!("Received ping from {}.", self.peer_addr());
+                self.send_pong(frame.into_data())
+            }
+            OpCode::Pong => {
+                trace!("Received pong from {}.", self.peer_addr());
+                self.handler.on_pong(frame.into_data())
+            }
+            OpCode::Extensions => self.handle_extensions(frame),
+            opcode => Err(Error::new(Kind::Protocol, format!("Received unexpected {} frame.", opcode))),
+        }
+    }
+
+    // Collect the fragments of a message, the whole message is returned with its last fragment.
+    fn reassemble(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if let OpCode::Continue = frame.opcode() {
+            if self.skip_fragments {
+                // the rest of a message that already failed
+                self.skip_fragments = !frame.is_final();
+                return Ok(None);
+            }
+            if self.fragments_in.is_empty() {
+                return Err(Error::new(Kind::Protocol, "Received continuation frame without a fragmented message."));
+            }
+            if frame.is_compressed() {
+                return Err(Error::new(Kind::Protocol, "Received compressed continuation frame."));
+            }
+        } else {
+            if !self.fragments_in.is_empty() || self.skip_fragments {
+                return Err(Error::new(Kind::Protocol, format!("Received {} frame while waiting for the rest of a fragmented message.", frame.opcode())));
+            }
+            if frame.is_final() {
+                return Ok(Some(frame));
+            }
+        }
+
+        if self.fragments_in.len() >= self.settings.fragments_capacity && !self.settings.fragments_grow {
+            self.fragments_in.clear();
+            self.fragments_size = 0;
+            self.skip_fragments = !frame.is_final();
+            return Err(Error::new(Kind::Capacity, "Exceeded max fragments."));
+        }
+        if self.fragments_size.saturating_add(frame.len()) > self.settings.max_frame_length {
+            self.fragments_in.clear();
+            self.fragments_size = 0;
+            self.skip_fragments = !frame.is_final();
+            return Err(Error::new(Kind::Capacity, "Fragmented message exceeds the maximum frame length."));
+        }
+
+        let finished = frame.is_final();
+        self.fragments_size += frame.len();
+        self.fragments_in.push_back(frame);
+        if !finished {
+            trace!("Buffered fragment {} of message from {}.", self.fragments_in.len(), self.peer_addr());
+            return Ok(None);
+        }
+
+        let size = self.fragments_size;
+        self.fragments_size = 0;
+        let (opcode, compressed) = {
+            let first = &self.fragments_in[0];
+            (first.opcode(), first.is_compressed())
+        };
+        let mut data = Vec::with_capacity(size);
+        for fragment in self.fragments_in.drain(..) {
+            data.extend(fragment.into_data());
+        }
+        trace!("Reassembled message of {} bytes from {}.", size, self.peer_addr());
+
+        let mut frame = Frame::message(data, opcode);
+        frame.set_compressed(compressed);
+        Ok(Some(frame))
+    }
+
+    // Announce the extensions we support, the other endpoint enables them for what it sends us.
+    fn send_extensions(&mut self) -> Result<()> {
+        #[cfg(feature = "permessage-deflate")]
+        {
+            if self.settings.compression && self.codec.supports_compression() {
+                self.decompressor = Some(Decompressor::new()?);
+                let frame = Frame::extensions(&[deflate::EXTENSION]);
+                self.check_buffer_out(frame.len())?;
+                self.codec.encode(frame, self.out_buffer.get_mut())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_extensions(&mut self, frame: Frame) -> Result<()> {
+        let names = frame.extension_names()?;
+        trace!("Connection to {} supports extensions {:?}.", self.peer_addr(), names);
+        #[cfg(feature = "permessage-deflate")]
+        {
+            if names.contains(&deflate::EXTENSION) && self.settings.compression && self.compressor.is_none() {
+                self.compressor = Some(Compressor::new()?);
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(unused_variables)]
+    fn inflate(&mut self, frame: Frame) -> Result<Frame> {
+        #[cfg(feature = "permessage-deflate")]
+        {
+            if let Some(ref mut decompressor) = self.decompressor {
+                let mut frame = frame;
+                let data = decompressor.decompress(frame.payload(), self.settings.max_frame_length)?;
+                frame.set_payload(data).set_compressed(false);
+                return Ok(frame);
+            }
+        }
+        Err(Error::new(Kind::Protocol, "Received compressed frame without announcing compression."))
+    }
+
+    // Compress large payloads once the other endpoint announced that it can inflate them.
+    #[allow(unused_variables)]
+    fn compress(&mut self, frame: &mut Frame) -> Result<()> {
+        #[cfg(feature = "permessage-deflate")]
+        {
+            if let Some(ref mut compressor) = self.compressor {
+                if frame.len() >= self.settings.compression_threshold {
+                    let data = compressor.compress(frame.payload())?;
+                    trace!("Compressed {} bytes to {}.", frame.len(), data.len());
+                    frame.set_payload(data).set_compressed(true);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_close(&mut self, frame: Frame) -> Result<()> {
+        let (code, reason) = frame.close_code()?;
+        match self.state {
+            Open => {
+                trace!("Received close {:?} -- {:?} from {}.", code, reason, self.peer_addr());
+                self.state = RespondingClose;
+                self.handler.on_close(code, reason);
+                // echo the code of the other endpoint
+                let code = if let CloseCode::Status = code { CloseCode::Empty } else { code };
+                self.send_close(code, "")
+            }
+            AwaitingClose => {
+                trace!("Received close {:?} -- {:?} answering our close from {}.", code, reason, self.peer_addr());
+                self.state = FinishedClose;
+                self.handler.on_close(code, reason);
+                self.check_close();
+                Ok(())
+            }
+            _ => {
+                trace!("Connection is already closing. Ignoring close {:?} -- {:?} from {}.", code, reason, self.peer_addr());
                 Ok(())
             }
-            Err(err) => Err(Error::from(err)),
         }
     }
 
     pub fn write(&mut self) -> Result<()> {
         if self.socket.is_negotiating() {
-            trace!("Performing TLS negotiation on {}.", self.peer_addr());
-            self.socket.clear_negotiating()
+            self.negotiate()
         } else {
             let res = if self.state.is_connecting() {
-                trace!("connect state not change {}.", self.peer_addr());
-                Err(Error::new(Kind::Internal, "connect state not change"))
+                if self.is_client() && !self.upgrading {
+                    self.finish_connect()
+                } else {
+                    self.write_handshake()
+                }
             } else {
                 trace!("Ready to write messages to {}.", self.peer_addr());
 
@@ -419,18 +958,13 @@ where
                 trace!("postions {:?}", self.out_buffer.position());
                 if let Some(len) = try!(self.socket.try_write_buf(&mut self.out_buffer)) {
                     trace!("Wrote {} bytes to {}", len, self.peer_addr());
-                    if len == 0 {
-                        match self.state {
-                            // we are are a server that is closing and just wrote out our confirming
-                            // close frame, let's disconnect
-                            FinishedClose if self.is_server() => return Ok(self.events = Ready::empty()),
-                            _ => (),
-                        }
-                    }
                 }
+                self.buffer_fragments()?;
 
                 // Check if there is more to write so that the connection will be rescheduled
-                Ok(self.check_events())
+                self.check_events();
+                self.check_close();
+                Ok(())
             };
 
             if self.socket.is_negotiating() && res.is_ok() {
@@ -441,6 +975,184 @@ where
         }
     }
 
+    fn negotiate(&mut self) -> Result<()> {
+        trace!("Performing TLS negotiation on {}.", self.peer_addr());
+        self.socket.clear_negotiating()?;
+        if self.socket.is_negotiating() {
+            self.check_negotiating();
+            Ok(())
+        } else {
+            trace!("TLS negotiation with {} finished.", self.peer_addr());
+            self.events = Ready::empty();
+            if !self.state.is_connecting() {
+                Ok(self.check_events())
+            } else if self.is_client() {
+                self.finish_connect()
+            } else {
+                self.open()
+            }
+        }
+    }
+
+    // Wait for whichever readiness the TLS handshake needs next.
+    fn check_negotiating(&mut self) {
+        if self.socket.is_negotiating() {
+            self.events = if self.socket.negotiation_wants_write() { Ready::writable() } else { Ready::readable() };
+        }
+    }
+
+    // Start the HTTP upgrade of a WebSocket, clients send their request while servers wait for one.
+    fn start_upgrade(&mut self) -> Result<()> {
+        self.upgrading = true;
+        let request = if let Client(ref url) = self.endpoint {
+            let url = match Url::parse(url) {
+                Ok(ref url) if url.has_host() => url.clone(),
+                // a plain host:port
+                _ => Url::parse(&format!("ws://{}", url))
+                    .map_err(|err| Error::new(Kind::Internal, format!("Unable to parse {} as url due to {:?}", url, err)))?,
+            };
+            Some(Request::from_url(&url)?)
+        } else {
+            None
+        };
+
+        if let Some(request) = request {
+            trace!("Sending WebSocket request to {}.", self.peer_addr());
+            if let Connecting(ref mut req, _) = self.state {
+                request.format(req.get_mut())?;
+            }
+            self.request = Some(request);
+            self.events = Ready::writable();
+        } else {
+            trace!("Waiting for WebSocket request from {}.", self.peer_addr());
+            self.events = Ready::readable();
+        }
+        Ok(())
+    }
+
+    fn read_handshake(&mut self) -> Result<()> {
+        let client = self.is_client();
+        let mut eof = false;
+        if let Connecting(ref mut req, ref mut res) = self.state {
+            let buf = if client { res } else { req };
+            while let Some(len) = self.socket.try_read_buf(buf.get_mut())? {
+                if len == 0 {
+                    eof = true;
+                    break;
+                }
+                if buf.get_ref().len() > MAX_HANDSHAKE {
+                    return Err(Error::new(Kind::Protocol, "WebSocket handshake exceeds the maximum size."));
+                }
+            }
+        }
+
+        let size = if client { self.read_response()? } else { self.read_request()? };
+        match size {
+            Some(size) => {
+                // frames the other endpoint sent right behind its handshake
+                if let Connecting(ref req, ref res) = self.state {
+                    let buf = if client { res } else { req };
+                    self.in_buffer.get_mut().extend(&buf.get_ref()[size..]);
+                }
+                if client {
+                    self.open()?;
+                    self.read_data()?;
+                }
+                Ok(())
+            }
+            None => {
+                if eof {
+                    trace!("Connection to {} closed during the WebSocket handshake.", self.peer_addr());
+                    self.disconnect();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn read_request(&mut self) -> Result<Option<usize>> {
+        let parsed = match self.state {
+            Connecting(ref req, _) => Request::parse(req.get_ref())?,
+            _ => None,
+        };
+        if let Some((request, size)) = parsed {
+            trace!("Received WebSocket request from {}:\n{}", self.peer_addr(), request);
+            request.validate()?;
+            let response = Response::from_request(&request)?;
+            if let Connecting(_, ref mut res) = self.state {
+                response.format(res.get_mut())?;
+            }
+            self.request = Some(request);
+            self.response = Some(response);
+            self.events = Ready::writable();
+            Ok(Some(size))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_response(&mut self) -> Result<Option<usize>> {
+        let parsed = match self.state {
+            Connecting(_, ref res) => Response::parse(res.get_ref())?,
+            _ => None,
+        };
+        if let Some((response, size)) = parsed {
+            trace!("Received WebSocket response from {}:\n{}", self.peer_addr(), response);
+            match self.request {
+                Some(ref request) => response.validate(request.key()?)?,
+                None => return Err(Error::new(Kind::Internal, "Received WebSocket response without sending a request.")),
+            }
+            self.response = Some(response);
+            self.upgrading = false;
+            Ok(Some(size))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_handshake(&mut self) -> Result<()> {
+        let client = self.is_client();
+        let done = if let Connecting(ref mut req, ref mut res) = self.state {
+            let buf = if client { req } else { res };
+            self.socket.try_write_buf(buf)?;
+            buf.position() as usize == buf.get_ref().len()
+        } else {
+            return Err(Error::new(Kind::Internal, "Tried to write WebSocket handshake while not connecting."));
+        };
+
+        if !done {
+            Ok(())
+        } else if !self.upgrading {
+            // the error response went out, there is nothing left to say
+            self.disconnect();
+            Ok(())
+        } else if client {
+            trace!("Waiting for WebSocket response from {}.", self.peer_addr());
+            self.events = Ready::readable();
+            Ok(())
+        } else {
+            self.upgrading = false;
+            self.open()?;
+            if self.in_buffer.get_ref().is_empty() {
+                Ok(())
+            } else {
+                self.read_data()
+            }
+        }
+    }
+
+    // The first writable event of a client socket means the non-blocking connect has finished.
+    fn finish_connect(&mut self) -> Result<()> {
+        if let Some(err) = self.socket.take_error()? {
+            trace!("Unable to connect to {:?}: {}", self.endpoint, err);
+            return Err(Error::from(err));
+        }
+
+        trace!("Connected to {}.", self.peer_addr());
+        self.events.remove(Ready::writable());
+        self.open()
+    }
+
     pub fn send_message(&mut self, msg: Message) -> Result<()> {
         if self.state.is_closing() {
             trace!("Connection is closing. Ignoring request to send message {:?} to {}.", msg, self.peer_addr());
@@ -449,23 +1161,90 @@ where
 
         let opcode = msg.opcode();
         trace!("Message opcode {:?}", opcode);
-        let data = msg.into_data();
-
-        self.check_buffer_out(&data)?;
-        trace!("Buffering frame to {} : {:?}", self.peer_addr(), data);
-        //TODO 写数据。
-        let pos = self.out_buffer.position();
-        self.out_buffer.seek(SeekFrom::End(0))?;
-        match self.out_buffer.write(&data) {
-            Ok(buffer_size) => {
-                //TODO
-                self.out_buffer.seek(SeekFrom::Start(pos))?;
-                Ok(self.check_events())
+
+        trace!("Buffering message to {} : {:?}", self.peer_addr(), msg);
+        let mut frame = Frame::from(msg);
+        self.compress(&mut frame)?;
+        if self.codec.supports_fragmentation() && self.settings.fragment_size > 0 && frame.len() > self.settings.fragment_size {
+            self.fragment(frame);
+        } else if !self.fragments_out.is_empty() {
+            // messages queue up behind the fragments of an earlier message
+            self.fragments_out.push_back(frame);
+        } else {
+            self.check_buffer_out(frame.len())?;
+            self.codec.encode(frame, self.out_buffer.get_mut())?;
+        }
+        self.buffer_fragments()?;
+        Ok(self.check_events())
+    }
+
+    fn fragment(&mut self, frame: Frame) {
+        let opcode = frame.opcode();
+        let compressed = frame.is_compressed();
+        let data = frame.into_data();
+        trace!("Fragmenting message of {} bytes to {}.", data.len(), self.peer_addr());
+
+        let mut chunks = data.chunks(self.settings.fragment_size).peekable();
+        let mut first = true;
+        while let Some(chunk) = chunks.next() {
+            let mut fragment = Frame::message(chunk.to_vec(), if first { opcode } else { OpCode::Continue });
+            fragment.set_compressed(first && compressed).set_final(chunks.peek().is_none());
+            self.fragments_out.push_back(fragment);
+            first = false;
+        }
+    }
+
+    // Move queued frames into the output buffer while it holds less than a fragment, control
+    // frames are buffered directly and so go out before the remaining fragments.
+    fn buffer_fragments(&mut self) -> Result<()> {
+        while !self.fragments_out.is_empty() && self.pending_out() < self.settings.fragment_size.max(1) {
+            if let Some(frame) = self.fragments_out.pop_front() {
+                self.check_buffer_out(frame.len())?;
+                self.codec.encode(frame, self.out_buffer.get_mut())?;
             }
-            Err(err) => Err(Error::from(err)),
         }
+        Ok(())
     }
 
+    #[inline]
+    fn pending_out(&self) -> usize {
+        self.out_buffer.get_ref().len() - self.out_buffer.position() as usize
+    }
+
+    /// The number of bytes still waiting to be written to the other endpoint, including queued
+    /// fragments.
+    pub fn unsent(&self) -> usize {
+        self.pending_out() + self.fragments_out.iter().map(Frame::len).sum::<usize>()
+    }
+
+
+    pub fn send_ping(&mut self, data: Vec<u8>) -> Result<()> {
+        if self.state.is_closing() {
+            trace!("Connection is closing. Ignoring request to send ping {:?} to {}.", data, self.peer_addr());
+            return Ok(());
+        }
+        trace!("Sending ping to {}.", self.peer_addr());
+        self.send_control(Frame::ping(data))
+    }
+
+    pub fn send_pong(&mut self, data: Vec<u8>) -> Result<()> {
+        if self.state.is_closing() {
+            trace!("Connection is closing. Ignoring request to send pong {:?} to {}.", data, self.peer_addr());
+            return Ok(());
+        }
+        trace!("Sending pong to {}.", self.peer_addr());
+        self.send_control(Frame::pong(data))
+    }
+
+    // Control frames skip the queue of fragments waiting to go out.
+    fn send_control(&mut self, frame: Frame) -> Result<()> {
+        if !self.codec.supports_control_frames() {
+            return Err(Error::new(Kind::Internal, format!("Unable to send {} frame, the codec has no control frames.", frame.opcode())));
+        }
+        self.check_buffer_out(frame.len())?;
+        self.codec.encode(frame, self.out_buffer.get_mut())?;
+        Ok(self.check_events())
+    }
 
     #[inline]
     pub fn send_close<R>(&mut self, code: CloseCode, reason: R) -> Result<()>
@@ -489,21 +1268,58 @@ where
         }
 
         trace!("Sending close {:?} -- {:?} to {}.", code, reason.borrow(), self.peer_addr());
+        self.cancel_keepalive();
+
+        if self.codec.supports_control_frames() {
+            let frame = Frame::close(code, reason.borrow());
+            if self.fragments_out.is_empty() {
+                self.check_buffer_out(frame.len())?;
+                self.codec.encode(frame, self.out_buffer.get_mut())?;
+            } else {
+                // the close goes out after the messages that are still queued
+                self.fragments_out.push_back(frame);
+            }
+        }
 
-        //TODO
-        //        if let Some(frame) = try!(self.handler.buffer_frame(Frame::close(code, reason.borrow()))) {
-        //            try!(self.buffer_frame(frame));
-        //        }
+        if let AwaitingClose = self.state {
+            if self.settings.close_timeout > 0 {
+                self.timers.push(TimerRequest::Arm(Timer::Close, self.settings.close_timeout));
+            }
+        }
 
         trace!("Connection to {} is now closing.", self.peer_addr());
 
-        Ok(self.check_events())
+        self.check_events();
+        self.check_close();
+        Ok(())
+    }
+
+    // Advance the close handshake once everything buffered has been written.
+    fn check_close(&mut self) {
+        if self.pending_out() > 0 || !self.fragments_out.is_empty() {
+            return;
+        }
+
+        match self.state {
+            FinishedClose => {
+                trace!("Close handshake with {} finished.", self.peer_addr());
+                self.cancel_close_timeout();
+                self.events = Ready::empty();
+            }
+            AwaitingClose if !self.codec.supports_control_frames() => {
+                trace!("Shutting down writing half of connection to {}.", self.peer_addr());
+                if let Err(err) = self.socket.shutdown(Shutdown::Write) {
+                    trace!("Unable to shut down connection to {}: {}", self.peer_addr(), err);
+                }
+            }
+            _ => (),
+        }
     }
 
     fn check_events(&mut self) {
         if !self.state.is_connecting() {
             self.events.insert(Ready::readable());
-            if self.out_buffer.position() < self.out_buffer.get_ref().len() as u64 {
+            if self.pending_out() > 0 || !self.fragments_out.is_empty() {
                 trace!("check_event{:?}- {:?}-", self.out_buffer.get_ref().len(), self.out_buffer.get_ref());
                 self.events.insert(Ready::writable());
             }
@@ -511,8 +1327,8 @@ where
     }
 
 
-    fn check_buffer_out(&mut self, frame: &Vec<u8>) -> Result<()> {
-        if self.out_buffer.get_ref().capacity() <= self.out_buffer.get_ref().len() + frame.len() {
+    fn check_buffer_out(&mut self, size: usize) -> Result<()> {
+        if self.out_buffer.get_ref().capacity() <= self.out_buffer.get_ref().len() + size {
             // extend
             let mut new = Vec::with_capacity(self.out_buffer.get_ref().capacity());
             new.extend(&self.out_buffer.get_ref()[self.out_buffer.position() as usize..]);
@@ -534,7 +1350,8 @@ where
         while let Some(len) = self.socket.try_read_buf(self.in_buffer.get_mut())? {
             trace!("try read buffer len {:?}, data {:?}", len, self.in_buffer.get_ref());
             if len == 0 {
-                return Ok(Some(self.in_buffer.get_ref().len()));
+                // end of stream
+                return Ok(Some(0));
             } else {
                 if self.in_buffer.get_ref().len() == self.in_buffer.get_ref().capacity() {
                     // extend
@@ -556,3 +1373,226 @@ where
         Ok(None)
     }
 }
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use codec::{Framed, Raw};
+    use mio;
+    use std::net::{TcpListener, TcpStream as StdStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    struct Collect {
+        received: Arc<Mutex<(usize, bool)>>,
+    }
+
+    impl Handler for Collect {
+        fn on_message(&mut self, msg: Message) -> Result<()> {
+            self.received.lock().unwrap().0 += msg.len();
+            Ok(())
+        }
+
+        fn on_close(&mut self, _: CloseCode, _: &str) {
+            self.received.lock().unwrap().1 = true;
+        }
+    }
+
+    struct Timeouts {
+        fired: Arc<Mutex<Vec<Token>>>,
+    }
+
+    impl Handler for Timeouts {
+        fn on_timeout(&mut self, event: Token) -> Result<()> {
+            self.fired.lock().unwrap().push(event);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handler_timeouts_stay_apart() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = StdStream::connect(listener.local_addr().unwrap()).unwrap();
+        let sock = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let handler = Timeouts { fired: fired.clone() };
+        let mut conn = Connection::new(Token(0), sock, handler, Settings::default(), 0, Box::new(Raw::default()));
+        conn.as_server().unwrap();
+        conn.open().unwrap();
+
+        // tokens that look like the internal timers still belong to the handler
+        for &token in &[Token(usize::MAX - 1), Token(usize::MAX - 2), Token(usize::MAX - 3)] {
+            conn.timeout_triggered(token).unwrap();
+        }
+        assert_eq!(*fired.lock().unwrap(), vec![Token(usize::MAX - 1), Token(usize::MAX - 2), Token(usize::MAX - 3)]);
+        assert!(conn.state.is_open());
+
+        // a close timer of a connection that is not closing does nothing
+        conn.timer_triggered(Timer::Close).unwrap();
+        assert!(conn.state.is_open());
+    }
+
+    struct Closed {
+        closed: Arc<Mutex<Option<(CloseCode, String)>>>,
+    }
+
+    impl Handler for Closed {
+        fn on_close(&mut self, code: CloseCode, reason: &str) {
+            let mut closed = self.closed.lock().unwrap();
+            assert!(closed.is_none(), "on_close called twice");
+            *closed = Some((code, reason.to_owned()));
+        }
+    }
+
+    #[test]
+    fn keepalive_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = StdStream::connect(listener.local_addr().unwrap()).unwrap();
+        let sock = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+
+        let mut settings = Settings::default();
+        settings.keepalive_interval = 10;
+        settings.keepalive_misses = 2;
+        let closed = Arc::new(Mutex::new(None));
+        let handler = Closed { closed: closed.clone() };
+        let mut conn = Connection::new(Token(0), sock, handler, settings, 0, Box::new(Framed::default()));
+        conn.as_server().unwrap();
+        conn.open().unwrap();
+
+        // every interval pings the silent peer and asks for the next one
+        for _ in 0..2 {
+            match conn.take_timers().as_slice() {
+                [TimerRequest::Arm(Timer::Keepalive, 10)] => (),
+                timers => panic!("unexpected {:?}", timers),
+            }
+            conn.timer_triggered(Timer::Keepalive).unwrap();
+            conn.write().unwrap();
+            assert!(closed.lock().unwrap().is_none());
+        }
+        match conn.take_timers().as_slice() {
+            [TimerRequest::Arm(Timer::Keepalive, 10)] => (),
+            timers => panic!("unexpected {:?}", timers),
+        }
+
+        // the interval after the second unanswered ping gives up
+        conn.timer_triggered(Timer::Keepalive).unwrap();
+        assert_eq!(*closed.lock().unwrap(), Some((CloseCode::Away, "Keepalive timed out.".to_owned())));
+        assert!(conn.events().is_empty());
+
+        let opcodes = frames_until_close(&mut peer).iter().map(Frame::opcode).collect::<Vec<OpCode>>();
+        assert_eq!(opcodes, vec![OpCode::Ping, OpCode::Ping, OpCode::Close]);
+    }
+
+    // the frames the peer receives up to and including a close frame
+    fn frames_until_close(peer: &mut StdStream) -> Vec<Frame> {
+        let mut input = Cursor::new(Vec::new());
+        let mut buf = [0u8; 256];
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut codec = Framed::default();
+        let mut frames: Vec<Frame> = Vec::new();
+        while frames.last().map(Frame::opcode) != Some(OpCode::Close) {
+            let len = io::Read::read(peer, &mut buf).unwrap();
+            assert!(len > 0, "the close frame never arrived");
+            input.get_mut().extend_from_slice(&buf[..len]);
+            while let Some(frame) = codec.decode(&mut input).unwrap() {
+                if frame.opcode() != OpCode::Extensions {
+                    frames.push(frame);
+                }
+            }
+        }
+        frames
+    }
+
+    fn fragment(data: &[u8], opcode: OpCode, finished: bool) -> Frame {
+        let mut frame = Frame::message(data.to_vec(), opcode);
+        frame.set_final(finished);
+        frame
+    }
+
+    #[test]
+    fn fragmented_message_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = StdStream::connect(listener.local_addr().unwrap()).unwrap();
+        let sock = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+
+        let mut settings = Settings::default();
+        settings.max_frame_length = 10;
+        let handler = Collect { received: Arc::new(Mutex::new((0, false))) };
+        let mut conn = Connection::new(Token(0), sock, handler, settings, 0, Box::new(Raw::default()));
+
+        assert!(conn.reassemble(fragment(b"1234", OpCode::Binary, false)).unwrap().is_none());
+        assert!(conn.reassemble(fragment(b"5678", OpCode::Continue, false)).unwrap().is_none());
+        match conn.reassemble(fragment(b"9abc", OpCode::Continue, false)) {
+            Err(Error { kind: Kind::Capacity, .. }) => (),
+            res => panic!("unexpected {:?}", res),
+        }
+        // the rest of the message is dropped, the next one starts over
+        assert!(conn.reassemble(fragment(b"def", OpCode::Continue, true)).unwrap().is_none());
+        let frame = conn.reassemble(fragment(b"12345", OpCode::Binary, true)).unwrap().unwrap();
+        assert_eq!(frame.len(), 5);
+    }
+
+    #[test]
+    fn data_before_eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = StdStream::connect(listener.local_addr().unwrap()).unwrap();
+        let sock = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+
+        let mut settings = Settings::default();
+        settings.raw_opcode = OpCode::Binary;
+        let received = Arc::new(Mutex::new((0, false)));
+        let handler = Collect { received: received.clone() };
+        let mut conn = Connection::new(Token(0), sock, handler, settings, 0, Box::new(Raw::default()));
+        conn.as_server().unwrap();
+        conn.open().unwrap();
+
+        // more than fits the input buffer at once, followed by the end of the stream
+        peer.write_all(&vec![7u8; settings.in_buffer_capacity * 4]).unwrap();
+        peer.shutdown(Shutdown::Write).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        while !received.lock().unwrap().1 {
+            conn.read().unwrap();
+        }
+        assert_eq!(received.lock().unwrap().0, settings.in_buffer_capacity * 4);
+    }
+
+    #[test]
+    fn fragments_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = StdStream::connect(listener.local_addr().unwrap()).unwrap();
+        let sock = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+
+        let mut settings = Settings::default();
+        settings.fragment_size = 4;
+        let handler = Collect { received: Arc::new(Mutex::new((0, false))) };
+        let mut conn = Connection::new(Token(0), sock, handler, settings, 0, Box::new(Framed::default()));
+        conn.as_server().unwrap();
+        conn.open().unwrap();
+        while conn.events().is_writable() {
+            conn.write().unwrap();
+        }
+
+        // the ping skips the queued fragments, the close waits for them
+        conn.send_message(Message::binary(b"0123456789".to_vec())).unwrap();
+        conn.send_ping(b"p".to_vec()).unwrap();
+        conn.send_close(CloseCode::Normal, "").unwrap();
+        while conn.events().is_writable() {
+            conn.write().unwrap();
+        }
+
+        let frames = frames_until_close(&mut peer);
+        let sent = frames.iter().map(|frame| (frame.opcode(), frame.is_final(), frame.payload().to_vec())).collect::<Vec<_>>();
+        assert_eq!(sent[..4], [
+            (OpCode::Binary, false, b"0123".to_vec()),
+            (OpCode::Ping, true, b"p".to_vec()),
+            (OpCode::Continue, false, b"4567".to_vec()),
+            (OpCode::Continue, true, b"89".to_vec()),
+        ]);
+        assert_eq!(sent[4].0, OpCode::Close);
+        assert_eq!(sent.len(), 5);
+    }
+}
@@ -0,0 +1,233 @@
+//! Raw deflate streams compressing message payloads for the `permessage-deflate` feature.
+//!
+//! Both directions keep their window between messages, so payloads repeating earlier ones
+//! shrink to a few bytes. Every compressed payload is sync flushed and the empty stored block
+//! that ends a sync flush is left out on the wire, the `Decompressor` adds it back.
+
+use libc::{self, c_int, size_t};
+use libz_sys as ffi;
+use result::{Result, Error, Kind};
+use std::mem;
+use std::ptr;
+
+/// The name under which endpoints announce support for compression.
+pub const EXTENSION: &str = "permessage-deflate";
+
+const WINDOW_BITS: c_int = 15;
+const MEM_LEVEL: c_int = 8;
+const CHUNK: usize = 4096;
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+unsafe extern "C" fn zalloc(_: ffi::voidpf, items: ffi::uInt, size: ffi::uInt) -> ffi::voidpf {
+    libc::calloc(items as size_t, size as size_t)
+}
+
+unsafe extern "C" fn zfree(_: ffi::voidpf, address: ffi::voidpf) {
+    libc::free(address)
+}
+
+// zlib keeps a pointer to the stream, so it has to stay in place once initialized.
+fn new_stream() -> Box<ffi::z_stream> {
+    Box::new(ffi::z_stream {
+        next_in: ptr::null_mut(),
+        avail_in: 0,
+        total_in: 0,
+        next_out: ptr::null_mut(),
+        avail_out: 0,
+        total_out: 0,
+        msg: ptr::null_mut(),
+        state: ptr::null_mut(),
+        zalloc,
+        zfree,
+        opaque: ptr::null_mut(),
+        data_type: 0,
+        adler: 0,
+        reserved: 0,
+    })
+}
+
+fn check(res: c_int, what: &'static str) -> Result<()> {
+    match res {
+        ffi::Z_OK | ffi::Z_BUF_ERROR => Ok(()),
+        // a final block ends the stream, which would leave the rest of the input unread
+        ffi::Z_STREAM_END => Err(Error::new(Kind::Protocol, "Received compressed data ending the stream.")),
+        ffi::Z_DATA_ERROR => Err(Error::new(Kind::Protocol, "Received invalid compressed data.")),
+        ffi::Z_MEM_ERROR => Err(Error::new(Kind::Capacity, format!("Out of memory while trying to {}.", what))),
+        res => Err(Error::new(Kind::Internal, format!("Unable to {}, zlib returned {}.", what, res))),
+    }
+}
+
+// Run a sync flush over all of the input, growing the output as needed. zlib counts in
+// `uInt`, so larger inputs are fed in pieces.
+fn flush<F>(stream: &mut ffi::z_stream, input: &[u8], output: &mut Vec<u8>, max_len: usize, mut step: F) -> Result<()>
+where
+    F: FnMut(&mut ffi::z_stream) -> c_int,
+{
+    for piece in input.chunks(ffi::uInt::MAX as usize) {
+        stream.next_in = piece.as_ptr() as *mut _;
+        stream.avail_in = piece.len() as ffi::uInt;
+        loop {
+            if output.capacity() == output.len() {
+                output.reserve(CHUNK);
+            }
+            let len = output.len();
+            // never produce more than one byte past the limit
+            let avail = (output.capacity() - len).min(max_len.saturating_sub(len).saturating_add(1)).min(ffi::uInt::MAX as usize);
+            stream.next_out = unsafe { output.as_mut_ptr().add(len) };
+            stream.avail_out = avail as ffi::uInt;
+            let avail_in = stream.avail_in;
+
+            let res = step(stream);
+            unsafe { output.set_len(len + avail - stream.avail_out as usize) };
+            if let Err(err) = check(res, "process compressed data") {
+                stream.next_in = ptr::null_mut();
+                stream.next_out = ptr::null_mut();
+                return Err(err);
+            }
+
+            if output.len() > max_len {
+                stream.next_in = ptr::null_mut();
+                stream.next_out = ptr::null_mut();
+                return Err(Error::new(Kind::Capacity, "Decompressed message exceeds the maximum frame length."));
+            }
+            // a flush is complete once zlib stops filling the output
+            if stream.avail_in == 0 && stream.avail_out > 0 {
+                break;
+            }
+            if stream.avail_in == avail_in && output.len() == len {
+                stream.next_in = ptr::null_mut();
+                stream.next_out = ptr::null_mut();
+                if stream.avail_in == 0 {
+                    break;
+                }
+                return Err(Error::new(Kind::Protocol, "Compressed data stopped making progress."));
+            }
+        }
+    }
+    stream.next_in = ptr::null_mut();
+    stream.next_out = ptr::null_mut();
+    Ok(())
+}
+
+/// Compresses outgoing payloads.
+pub struct Compressor {
+    stream: Box<ffi::z_stream>,
+}
+
+impl Compressor {
+    pub fn new() -> Result<Compressor> {
+        let mut stream = new_stream();
+        let res = unsafe {
+            ffi::deflateInit2_(&mut *stream,
+                               ffi::Z_DEFAULT_COMPRESSION,
+                               ffi::Z_DEFLATED,
+                               -WINDOW_BITS,
+                               MEM_LEVEL,
+                               ffi::Z_DEFAULT_STRATEGY,
+                               ffi::zlibVersion(),
+                               mem::size_of::<ffi::z_stream>() as c_int)
+        };
+        check(res, "initialize compression")?;
+        Ok(Compressor { stream })
+    }
+
+    pub fn compress(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(input.len() / 2 + TRAILER.len());
+        flush(&mut self.stream, input, &mut output, usize::MAX, |stream| unsafe { ffi::deflate(stream, ffi::Z_SYNC_FLUSH) })?;
+        if output.ends_with(&TRAILER) {
+            let len = output.len() - TRAILER.len();
+            output.truncate(len);
+        }
+        Ok(output)
+    }
+}
+
+// SAFETY: the `z_stream` and the zlib state behind its raw pointers are allocated for this
+// compressor alone and never shared, they are only used through `&mut self`. zlib keeps no
+// thread local state, so the stream may be used from whichever thread owns the compressor.
+unsafe impl Send for Compressor {}
+
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::deflateEnd(&mut *self.stream);
+        }
+    }
+}
+
+/// Inflates incoming payloads.
+pub struct Decompressor {
+    stream: Box<ffi::z_stream>,
+}
+
+impl Decompressor {
+    pub fn new() -> Result<Decompressor> {
+        let mut stream = new_stream();
+        let res = unsafe { ffi::inflateInit2_(&mut *stream, -WINDOW_BITS, ffi::zlibVersion(), mem::size_of::<ffi::z_stream>() as c_int) };
+        check(res, "initialize decompression")?;
+        Ok(Decompressor { stream })
+    }
+
+    /// Inflate a payload, failing with a capacity error once the result grows past `max_len`.
+    pub fn decompress(&mut self, input: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(input.len() * 2);
+        let mut data = Vec::with_capacity(input.len() + TRAILER.len());
+        data.extend_from_slice(input);
+        data.extend_from_slice(&TRAILER);
+        flush(&mut self.stream, &data, &mut output, max_len, |stream| unsafe { ffi::inflate(stream, ffi::Z_SYNC_FLUSH) })?;
+        Ok(output)
+    }
+}
+
+// SAFETY: as for the `Compressor`, the stream belongs to this decompressor alone.
+unsafe impl Send for Decompressor {}
+
+impl Drop for Decompressor {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::inflateEnd(&mut *self.stream);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut compressor = Compressor::new().unwrap();
+        let mut decompressor = Decompressor::new().unwrap();
+        let state = b"{\"height\": 42, \"peers\": [1, 2, 3], \"state\": \"synced\"}".repeat(100);
+
+        let first = compressor.compress(&state).unwrap();
+        assert!(first.len() < state.len() / 10);
+        assert_eq!(decompressor.decompress(&first, usize::MAX).unwrap(), state);
+
+        // the window carries over, so the same payload shrinks further
+        let second = compressor.compress(&state).unwrap();
+        assert!(second.len() < first.len());
+        assert_eq!(decompressor.decompress(&second, usize::MAX).unwrap(), state);
+    }
+
+    #[test]
+    fn decompress_limit() {
+        let mut compressor = Compressor::new().unwrap();
+        let mut decompressor = Decompressor::new().unwrap();
+        let data = compressor.compress(&vec![0; 100_000]).unwrap();
+        assert!(decompressor.decompress(&data, 1000).is_err());
+        assert_eq!(Decompressor::new().unwrap().decompress(&data, 100_000).unwrap().len(), 100_000);
+        assert!(Decompressor::new().unwrap().decompress(b"\xff\xff\xff", usize::MAX).is_err());
+    }
+
+    #[test]
+    fn final_block() {
+        // an empty stored block with BFINAL set ends the stream before the trailer is read
+        let mut decompressor = Decompressor::new().unwrap();
+        match decompressor.decompress(&[0x01, 0x00, 0x00, 0xff, 0xff], 1 << 20) {
+            Err(Error { kind: Kind::Protocol, .. }) => (),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! A front end that hands network events to a channel instead of `Handler` callbacks.
+//!
+//! `Builder::build_events` supplies the `Factory` and `Handler` itself. The events of every
+//! connection arrive on one `Receiver`, tagged with the id of the connection, and the `Outbox`
+//! answers any connection by that id. Both may live on other threads than the event loop.
+
+use communication::Sender;
+use factory::Factory;
+use handler::Handler;
+use handshake::Handshake;
+use message::Message;
+use protocol::CloseCode;
+use result::{Result, Error, Kind};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use util::Token;
+
+type Senders = Arc<Mutex<HashMap<u32, Sender>>>;
+
+/// Something that happened on a connection, tagged with the id of the connection.
+#[derive(Debug)]
+pub enum Event {
+    /// The connection is open.
+    Opened(u32, Handshake),
+    /// A message arrived.
+    Message(u32, Message),
+    /// The connection closed with the given code and reason.
+    Closed(u32, CloseCode, String),
+    /// An error occurred on the connection, or it could not be opened.
+    Error(u32, Error),
+    /// A timeout scheduled through `Outbox::timeout` expired.
+    Timeout(u32, Token),
+}
+
+impl Event {
+    /// The id of the connection the event belongs to.
+    pub fn connection_id(&self) -> u32 {
+        match *self {
+            Event::Opened(id, _) |
+            Event::Message(id, _) |
+            Event::Closed(id, _, _) |
+            Event::Error(id, _) |
+            Event::Timeout(id, _) => id,
+        }
+    }
+}
+
+/// Replies to connections by their id from any thread.
+#[derive(Clone)]
+pub struct Outbox {
+    broadcaster: Sender,
+    senders: Senders,
+}
+
+impl Outbox {
+    /// The sender of a connection that has not been lost yet.
+    pub fn sender(&self, connection_id: u32) -> Result<Sender> {
+        self.senders
+            .lock()
+            .map_err(|_| Error::new(Kind::Internal, "The connections of the outbox are poisoned."))?
+            .get(&connection_id)
+            .cloned()
+            .ok_or_else(|| Error::new(Kind::Internal, format!("No connection with id {}.", connection_id)))
+    }
+
+    /// Send a message to one connection.
+    pub fn send<M>(&self, connection_id: u32, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        self.sender(connection_id)?.send(msg)
+    }
+
+    /// Send a message to every connection of the event loop.
+    pub fn broadcast<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        self.broadcaster.broadcast(msg)
+    }
+
+    /// Close one connection.
+    pub fn close(&self, connection_id: u32, code: CloseCode) -> Result<()> {
+        self.sender(connection_id)?.close(code)
+    }
+
+    /// Close one connection with a reason.
+    pub fn close_with_reason<S>(&self, connection_id: u32, code: CloseCode, reason: S) -> Result<()>
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.sender(connection_id)?.close_with_reason(code, reason)
+    }
+
+    /// Deliver `Event::Timeout` with `token` for a connection after `ms` milliseconds.
+    pub fn timeout(&self, connection_id: u32, ms: u64, token: Token) -> Result<()> {
+        self.sender(connection_id)?.timeout(ms, token)
+    }
+
+    /// Queue a new outbound connection, its events arrive like those of any other.
+    pub fn connect(&self, url: String) -> Result<()> {
+        self.broadcaster.connect(url)
+    }
+
+    /// Stop the event loop right away.
+    pub fn shutdown(&self) -> Result<()> {
+        self.broadcaster.shutdown()
+    }
+}
+
+/// The `Factory` behind `Builder::build_events`.
+pub struct EventFactory {
+    events: mpsc::Sender<Event>,
+    senders: Senders,
+}
+
+impl EventFactory {
+    pub fn new(events: mpsc::Sender<Event>) -> EventFactory {
+        EventFactory {
+            events,
+            senders: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The outbox answering the connections of this factory through the event loop `broadcaster`.
+    pub fn outbox(&self, broadcaster: Sender) -> Outbox {
+        Outbox {
+            broadcaster,
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+impl Factory for EventFactory {
+    type Handler = EventHandler;
+
+    fn connection_made(&mut self, out: Sender) -> EventHandler {
+        let connection_id = out.connection_id();
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.insert(connection_id, out);
+        }
+        EventHandler {
+            connection_id,
+            events: self.events.clone(),
+        }
+    }
+
+    fn connection_lost(&mut self, handler: EventHandler) {
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.remove(&handler.connection_id);
+        }
+    }
+}
+
+/// The `Handler` passing the events of one connection on to the channel.
+pub struct EventHandler {
+    connection_id: u32,
+    events: mpsc::Sender<Event>,
+}
+
+impl EventHandler {
+    fn deliver(&self, event: Event) {
+        if self.events.send(event).is_err() {
+            debug!("Dropping event of connection {}, the receiver is gone.", self.connection_id);
+        }
+    }
+}
+
+impl Handler for EventHandler {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.deliver(Event::Opened(self.connection_id, shake));
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.deliver(Event::Message(self.connection_id, msg));
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.deliver(Event::Closed(self.connection_id, code, reason.to_owned()));
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.deliver(Event::Error(self.connection_id, err));
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.deliver(Event::Timeout(self.connection_id, event));
+        Ok(())
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use communication::Signal;
+    use mio;
+
+    #[test]
+    fn route_by_connection_id() {
+        let (queue_tx, queue_rx) = mio::channel::sync_channel(8);
+        let (events_tx, events_rx) = mpsc::channel();
+        let mut factory = EventFactory::new(events_tx);
+        let outbox = factory.outbox(Sender::new(Token(0), queue_tx.clone(), 0));
+
+        let mut handler = factory.connection_made(Sender::new(Token(3), queue_tx.clone(), 7));
+        handler.on_message(Message::text("ping")).unwrap();
+        match events_rx.try_recv().unwrap() {
+            Event::Message(7, msg) => assert_eq!(msg, Message::text("ping")),
+            event => panic!("unexpected {:?}", event),
+        }
+
+        outbox.send(7, "pong").unwrap();
+        let cmd = queue_rx.try_recv().unwrap();
+        assert_eq!(cmd.token(), Token(3));
+        assert_eq!(cmd.connection_id(), 7);
+        match cmd.signal() {
+            Signal::Message(msg) => assert_eq!(msg, Message::text("pong")),
+            signal => panic!("unexpected {:?}", signal),
+        }
+
+        factory.connection_lost(handler);
+        assert!(outbox.send(7, "gone").is_err());
+    }
+}
@@ -1,5 +1,8 @@
 use communication::Sender;
 use handler::Handler;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{SslAcceptor, SslConnector};
+use std::net::SocketAddr;
 
 /// A trait for creating new Socket handlers.
 pub trait Factory {
@@ -27,8 +30,48 @@ pub trait Factory {
     }
 
 
+    /// Called when one of several listeners accepted a connection, `listener` is the local
+    /// address it is bound to. Defaults to `server_connected`.
+    #[inline]
+    fn listener_connected(&mut self, xnet: Sender, _listener: &SocketAddr) -> Self::Handler {
+        self.server_connected(xnet)
+    }
+
     #[inline]
     fn connection_lost(&mut self, _: Self::Handler) {}
+
+    /// Called when the process received SIGHUP, usually a request to reload the configuration.
+    /// Requires `Settings::handle_signals`.
+    #[inline]
+    fn on_hangup(&mut self) {
+        debug!("Factory received SIGHUP.");
+    }
+
+    /// Called before a lost client connection to `url` is dialed again as `Settings::reconnect`
+    /// allows, `attempt` counts from 1 since the connection was last open. The new connection
+    /// gets its handler from `client_connected`. Return false to give up on the url.
+    #[inline]
+    fn on_reconnect(&mut self, _url: &str, _attempt: u32) -> bool {
+        true
+    }
+
+    /// Called for every accepted connection with the address of the other endpoint.
+    /// Return an acceptor to negotiate TLS on the connection, `None` keeps it plain TCP.
+    /// `Handler::on_open` is called once the handshake finished.
+    #[cfg(feature = "ssl")]
+    #[inline]
+    fn ssl_acceptor(&mut self, _: &SocketAddr) -> Option<SslAcceptor> {
+        None
+    }
+
+    /// Called for every outbound connection with the url it is made to.
+    /// Return a connector to negotiate TLS on the connection, `None` keeps it plain TCP.
+    /// The host of the url is used for SNI and certificate verification.
+    #[cfg(feature = "ssl")]
+    #[inline]
+    fn ssl_connector(&mut self, _: &str) -> Option<SslConnector> {
+        None
+    }
 }
 
 impl<F, H> Factory for F
@@ -0,0 +1,211 @@
+use byteorder::{BigEndian, ByteOrder};
+use message::Message;
+use protocol::{CloseCode, OpCode};
+use result::{Result, Error, Kind};
+use std::fmt;
+use std::str::from_utf8;
+
+/// A single frame as produced and consumed by a `Codec`.
+///
+/// The opcode of a frame decides how its payload is delivered: text frames are validated as
+/// UTF-8 while binary frames are handed to the `Handler` untouched.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Frame {
+    opcode: OpCode,
+    finished: bool,
+    compressed: bool,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Create a new frame with any opcode, as done by codecs when decoding.
+    #[inline]
+    pub fn new(opcode: OpCode, payload: Vec<u8>) -> Frame {
+        Frame { opcode, finished: true, compressed: false, payload }
+    }
+
+    /// Create a new data frame.
+    #[inline]
+    pub fn message(data: Vec<u8>, opcode: OpCode) -> Frame {
+        debug_assert!(!opcode.is_control(), "Invalid opcode for data frame.");
+        Frame { opcode, finished: true, compressed: false, payload: data }
+    }
+
+    /// Create a new close control frame.
+    ///
+    /// The payload holds the close code as a big-endian u16 followed by the reason, it is empty
+    /// for `CloseCode::Empty`.
+    #[inline]
+    pub fn close(code: CloseCode, reason: &str) -> Frame {
+        let payload = if let CloseCode::Empty = code {
+            Vec::new()
+        } else {
+            let mut payload = vec![0; 2];
+            BigEndian::write_u16(&mut payload, code.into());
+            payload.extend(reason.as_bytes());
+            payload
+        };
+
+        Frame { opcode: OpCode::Close, finished: true, compressed: false, payload }
+    }
+
+    /// Create a new ping control frame.
+    #[inline]
+    pub fn ping(data: Vec<u8>) -> Frame {
+        Frame::new(OpCode::Ping, data)
+    }
+
+    /// Create a new pong control frame answering a ping with its data.
+    #[inline]
+    pub fn pong(data: Vec<u8>) -> Frame {
+        Frame::new(OpCode::Pong, data)
+    }
+
+    /// Create a new extensions control frame listing the names of the supported extensions.
+    #[inline]
+    pub fn extensions(names: &[&str]) -> Frame {
+        Frame { opcode: OpCode::Extensions, finished: true, compressed: false, payload: names.join(",").into_bytes() }
+    }
+
+    /// Parse the extension names out of an extensions frame, unknown names should be ignored.
+    pub fn extension_names(&self) -> Result<Vec<&str>> {
+        debug_assert!(self.opcode == OpCode::Extensions, "Attempted to parse extensions of a {} frame.", self.opcode);
+        Ok(from_utf8(&self.payload)?.split(',').map(str::trim).filter(|name| !name.is_empty()).collect())
+    }
+
+    /// Parse the close code and reason out of a close frame.
+    /// An empty payload yields `CloseCode::Status` as the other endpoint did not send a code.
+    /// The reserved codes 1005 and 1006 must not appear on the wire and are protocol errors.
+    pub fn close_code(&self) -> Result<(CloseCode, &str)> {
+        debug_assert!(self.opcode == OpCode::Close, "Attempted to parse close code of a {} frame.", self.opcode);
+        match self.payload.len() {
+            0 => Ok((CloseCode::Status, "")),
+            1 => Err(Error::new(Kind::Protocol, "Received close frame with invalid length.")),
+            _ => {
+                let raw = BigEndian::read_u16(&self.payload[..2]);
+                let code = CloseCode::from(raw);
+                match code {
+                    // reserved for endpoints to report a missing code or a dropped connection
+                    CloseCode::Status | CloseCode::Abnormal => {
+                        Err(Error::new(Kind::Protocol, format!("Received close frame with reserved code {}.", raw)))
+                    }
+                    _ => Ok((code, from_utf8(&self.payload[2..])?)),
+                }
+            }
+        }
+    }
+
+    /// Get the opcode of the frame.
+    #[inline]
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    /// Test whether the frame is the last fragment of its message, which unfragmented frames are.
+    #[inline]
+    pub fn is_final(&self) -> bool {
+        self.finished
+    }
+
+    /// Mark whether more fragments of the message follow this frame.
+    #[inline]
+    pub fn set_final(&mut self, finished: bool) -> &mut Frame {
+        self.finished = finished;
+        self
+    }
+
+    /// Test whether the payload of the frame is compressed.
+    #[inline]
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Mark the payload of the frame as compressed.
+    #[inline]
+    pub fn set_compressed(&mut self, compressed: bool) -> &mut Frame {
+        self.compressed = compressed;
+        self
+    }
+
+    /// Replace the payload of the frame, as done when compressing or inflating it.
+    #[inline]
+    pub fn set_payload(&mut self, payload: Vec<u8>) -> &mut Frame {
+        self.payload = payload;
+        self
+    }
+
+    /// Test whether the frame is a control frame.
+    #[inline]
+    pub fn is_control(&self) -> bool {
+        self.opcode.is_control()
+    }
+
+    /// Get the length of the payload.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Test whether the payload is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    /// Get a reference to the frame's payload.
+    #[inline]
+    pub fn payload(&self) -> &Vec<u8> {
+        &self.payload
+    }
+
+    /// Consume the frame into its payload.
+    #[inline]
+    pub fn into_data(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+impl From<Message> for Frame {
+    fn from(msg: Message) -> Frame {
+        let opcode = msg.opcode();
+        Frame::message(msg.into_data(), opcode)
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<FRAME> opcode: {} final: {} compressed: {} length: {}", self.opcode, self.finished, self.compressed, self.payload.len())
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+
+    #[test]
+    fn close_frame() {
+        let frame = Frame::close(CloseCode::Away, "bye");
+        assert_eq!(frame.opcode(), OpCode::Close);
+        assert_eq!(frame.payload(), &vec![0x03, 0xE9, b'b', b'y', b'e']);
+        assert_eq!(frame.close_code().unwrap(), (CloseCode::Away, "bye"));
+    }
+
+    #[test]
+    fn empty_close_frame() {
+        let frame = Frame::close(CloseCode::Empty, "ignored");
+        assert!(frame.is_empty());
+        assert_eq!(frame.close_code().unwrap(), (CloseCode::Status, ""));
+        assert!(Frame::new(OpCode::Close, vec![3]).close_code().is_err());
+        assert!(Frame::new(OpCode::Close, vec![0x03, 0xED]).close_code().is_err());
+        assert!(Frame::new(OpCode::Close, vec![0x03, 0xEE]).close_code().is_err());
+    }
+
+    #[test]
+    fn extensions_frame() {
+        let frame = Frame::extensions(&["permessage-deflate", "other"]);
+        assert!(frame.is_control());
+        assert_eq!(frame.extension_names().unwrap(), vec!["permessage-deflate", "other"]);
+        assert!(Frame::new(OpCode::Extensions, Vec::new()).extension_names().unwrap().is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use log::LogLevel::Error as ErrorLevel;
 
+use handshake::Handshake;
 use message::Message;
 use protocol::CloseCode;
 use result::{Result, Error, Kind};
@@ -15,7 +16,10 @@ pub trait Handler {
     }
 
     // Socket events
-    fn on_open(&mut self) -> Result<()> {
+    /// Called once the connection is established, right after accepting it for servers and once
+    /// the connect has finished for clients. Returning an error closes the connection.
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        debug!("Connection with {} now open", shake.peer_addr);
         Ok(())
     }
 
@@ -26,6 +30,14 @@ pub trait Handler {
     }
 
 
+    /// Called when the other endpoint answers a ping with the data of the ping.
+    /// Pings of the other endpoint are answered automatically.
+    #[inline]
+    fn on_pong(&mut self, data: Vec<u8>) -> Result<()> {
+        trace!("Received pong {:?}", data);
+        Ok(())
+    }
+
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         debug!("Connection closing due to ({:?}) {}", code, reason);
     }
@@ -45,6 +57,14 @@ pub trait Handler {
     }
 
 
+    /// Called when an outbound connection to `url` fails before it opened, because the other
+    /// endpoint could not be reached, did not answer within `Settings::connect_timeout` or
+    /// refused the handshake. By default the error is passed on to `on_error`.
+    fn on_connect_failed(&mut self, url: &str, err: Error) {
+        debug!("Unable to connect to {}", url);
+        self.on_error(err)
+    }
+
     // timeout events
     #[inline]
     fn on_timeout(&mut self, event: Token) -> Result<()> {
@@ -73,10 +93,12 @@ mod test {
     #![allow(unused_imports, unused_variables, dead_code)]
 
     use super::*;
+    use connection::Endpoint;
     use message;
     use mio;
     use protocol::CloseCode;
     use result::Result;
+    use std::time::SystemTime;
     use url;
 
     #[derive(Debug, Eq, PartialEq)]
@@ -93,7 +115,9 @@ mod test {
         struct H;
 
         impl Handler for H {
-            fn on_open(&mut self) -> Result<()> {
+            fn on_open(&mut self, shake: Handshake) -> Result<()> {
+                assert!(shake.is_client());
+                assert_eq!(shake.url(), Some("tcp://127.0.0.1:3012/"));
                 Ok(())
             }
 
@@ -107,8 +131,17 @@ mod test {
         }
 
         let mut h = H;
-        let url = url::Url::parse("127.0.0.1:3012").unwrap();
-        h.on_open().unwrap();
+        let url = url::Url::parse("tcp://127.0.0.1:3012").unwrap();
+        let shake = Handshake {
+            peer_addr: "127.0.0.1:3012".parse().unwrap(),
+            local_addr: "127.0.0.1:40000".parse().unwrap(),
+            endpoint: Endpoint::Client(url.as_str().into()),
+            connection_id: 0,
+            accepted_at: SystemTime::now(),
+            request: None,
+            response: None,
+        };
+        h.on_open(shake).unwrap();
         h.on_message(message::Message::Text("testme".to_owned())).unwrap();
         h.on_close(CloseCode::Normal, "");
     }
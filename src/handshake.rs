@@ -0,0 +1,355 @@
+use connection::Endpoint;
+use httparse;
+use rand;
+use result::{Result, Error, Kind};
+use sha1;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::from_utf8;
+use std::time::SystemTime;
+use url::Url;
+
+static WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+static BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const MAX_HEADERS: usize = 64;
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let indices = [bytes[0] >> 2, (bytes[0] & 0x03) << 4 | bytes[1] >> 4, (bytes[1] & 0x0F) << 2 | bytes[2] >> 6, bytes[2] & 0x3F];
+        for (i, &index) in indices.iter().enumerate() {
+            encoded.push(if i <= chunk.len() { BASE64[index as usize] as char } else { '=' });
+        }
+    }
+    encoded
+}
+
+/// Compute the `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`.
+pub fn hash_key(key: &[u8]) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(key);
+    hasher.update(WS_GUID.as_bytes());
+    encode_base64(&hasher.digest().bytes())
+}
+
+fn find_header<'a>(headers: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| &value[..])
+}
+
+// Whether a comma separated header holds the given token, as in `Connection: keep-alive, Upgrade`.
+fn header_contains(headers: &[(String, Vec<u8>)], name: &str, token: &str) -> bool {
+    headers.iter()
+           .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+           .filter_map(|(_, value)| from_utf8(value).ok())
+           .any(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+}
+
+fn collect_headers(headers: &[httparse::Header]) -> Vec<(String, Vec<u8>)> {
+    headers.iter().map(|header| (header.name.into(), header.value.into())).collect()
+}
+
+fn write_headers(headers: &[(String, Vec<u8>)], w: &mut dyn Write) -> Result<()> {
+    for (name, value) in headers {
+        write!(w, "{}: ", name)?;
+        w.write_all(value)?;
+        write!(w, "\r\n")?;
+    }
+    write!(w, "\r\n")?;
+    Ok(())
+}
+
+/// Information about a newly established connection, passed to `Handler::on_open`.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    /// The address of the other endpoint.
+    pub peer_addr: SocketAddr,
+    /// The local address of the connection.
+    pub local_addr: SocketAddr,
+    /// Whether the connection was dialed out with the url it was made to, or accepted.
+    pub endpoint: Endpoint,
+    /// The id of the connection, as also used by its `Sender`.
+    pub connection_id: u32,
+    /// The time the connection was accepted, for clients the time the connect finished.
+    pub accepted_at: SystemTime,
+    /// The opening request of a WebSocket connection.
+    pub request: Option<Request>,
+    /// The response to the opening request of a WebSocket connection.
+    pub response: Option<Response>,
+}
+
+impl Handshake {
+    /// Whether we dialed out to the other endpoint.
+    #[inline]
+    pub fn is_client(&self) -> bool {
+        match self.endpoint {
+            Endpoint::Client(_) => true,
+            Endpoint::Server => false,
+        }
+    }
+
+    /// Whether the other endpoint connected to us.
+    #[inline]
+    pub fn is_server(&self) -> bool {
+        !self.is_client()
+    }
+
+    /// The url a client connection was made to.
+    #[inline]
+    pub fn url(&self) -> Option<&str> {
+        match self.endpoint {
+            Endpoint::Client(ref url) => Some(url),
+            Endpoint::Server => None,
+        }
+    }
+}
+
+/// The HTTP request opening a WebSocket connection.
+#[derive(Debug, Clone)]
+pub struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Request {
+    /// Parse a request, returning it with the number of bytes it took up once it is complete.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Request, usize)>> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(buf)? {
+            httparse::Status::Complete(size) => {
+                let request = Request {
+                    method: req.method.unwrap_or("").into(),
+                    path: req.path.unwrap_or("").into(),
+                    headers: collect_headers(req.headers),
+                };
+                Ok(Some((request, size)))
+            }
+            httparse::Status::Partial => Ok(None),
+        }
+    }
+
+    /// Create the request opening a WebSocket connection to the given url with a fresh key.
+    pub fn from_url(url: &Url) -> Result<Request> {
+        let host = url.host_str().ok_or_else(|| Error::new(Kind::Internal, format!("Unable to open a WebSocket without a host: {}", url)))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.into(),
+        };
+        let mut path = url.path().to_owned();
+        if let Some(query) = url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        let key = encode_base64(&rand::random::<[u8; 16]>());
+
+        Ok(Request {
+            method: "GET".into(),
+            path,
+            headers: vec![("Host".into(), host.into()),
+                          ("Upgrade".into(), "websocket".into()),
+                          ("Connection".into(), "Upgrade".into()),
+                          ("Sec-WebSocket-Version".into(), "13".into()),
+                          ("Sec-WebSocket-Key".into(), key.into())],
+        })
+    }
+
+    /// Check that the request asks for a WebSocket connection.
+    pub fn validate(&self) -> Result<()> {
+        if self.method != "GET" {
+            return Err(Error::new(Kind::Protocol, format!("WebSocket requests must use GET, not {}.", self.method)));
+        }
+        if !header_contains(&self.headers, "Upgrade", "websocket") {
+            return Err(Error::new(Kind::Protocol, "Missing Upgrade: websocket header."));
+        }
+        if !header_contains(&self.headers, "Connection", "upgrade") {
+            return Err(Error::new(Kind::Protocol, "Missing Connection: upgrade header."));
+        }
+        if self.header("Sec-WebSocket-Version") != Some(b"13") {
+            return Err(Error::new(Kind::Protocol, "Unsupported WebSocket version, only 13 is supported."));
+        }
+        self.key().map(|_| ())
+    }
+
+    /// The method of the request.
+    #[inline]
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The path and query the request was made for.
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The value of a header, matched ignoring case.
+    #[inline]
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        find_header(&self.headers, name)
+    }
+
+    /// All headers in the order they were sent.
+    #[inline]
+    pub fn headers(&self) -> &Vec<(String, Vec<u8>)> {
+        &self.headers
+    }
+
+    /// The origin of a request made by a browser.
+    pub fn origin(&self) -> Option<&str> {
+        self.header("Origin").and_then(|origin| from_utf8(origin).ok())
+    }
+
+    /// The `Sec-WebSocket-Key` of the request.
+    pub fn key(&self) -> Result<&[u8]> {
+        self.header("Sec-WebSocket-Key").ok_or_else(|| Error::new(Kind::Protocol, "Missing Sec-WebSocket-Key header."))
+    }
+
+    /// Write the request as HTTP.
+    pub fn format(&self, w: &mut dyn Write) -> Result<()> {
+        write!(w, "{} {} HTTP/1.1\r\n", self.method, self.path)?;
+        write_headers(&self.headers, w)
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<REQUEST> {} {}", self.method, self.path)
+    }
+}
+
+/// The HTTP response to the opening request of a WebSocket connection.
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Response {
+    /// Parse a response, returning it with the number of bytes it took up once it is complete.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Response, usize)>> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut res = httparse::Response::new(&mut headers);
+        match res.parse(buf)? {
+            httparse::Status::Complete(size) => {
+                let response = Response {
+                    status: res.code.unwrap_or(0),
+                    reason: res.reason.unwrap_or("").into(),
+                    headers: collect_headers(res.headers),
+                };
+                Ok(Some((response, size)))
+            }
+            httparse::Status::Partial => Ok(None),
+        }
+    }
+
+    /// Create the response accepting a valid WebSocket request.
+    pub fn from_request(req: &Request) -> Result<Response> {
+        let accept = hash_key(req.key()?);
+        Ok(Response {
+            status: 101,
+            reason: "Switching Protocols".into(),
+            headers: vec![("Upgrade".into(), "websocket".into()),
+                          ("Connection".into(), "Upgrade".into()),
+                          ("Sec-WebSocket-Accept".into(), accept.into())],
+        })
+    }
+
+    /// Check that the response accepts the request that was made with the given key.
+    pub fn validate(&self, key: &[u8]) -> Result<()> {
+        if self.status != 101 {
+            return Err(Error::new(Kind::Protocol, format!("Server refused the WebSocket connection: {} {}", self.status, self.reason)));
+        }
+        if !header_contains(&self.headers, "Upgrade", "websocket") {
+            return Err(Error::new(Kind::Protocol, "Missing Upgrade: websocket header."));
+        }
+        if !header_contains(&self.headers, "Connection", "upgrade") {
+            return Err(Error::new(Kind::Protocol, "Missing Connection: upgrade header."));
+        }
+        match self.header("Sec-WebSocket-Accept") {
+            Some(accept) if accept == hash_key(key).as_bytes() => Ok(()),
+            Some(_) => Err(Error::new(Kind::Protocol, "Sec-WebSocket-Accept does not match the key.")),
+            None => Err(Error::new(Kind::Protocol, "Missing Sec-WebSocket-Accept header.")),
+        }
+    }
+
+    /// The status code of the response.
+    #[inline]
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The reason phrase of the response.
+    #[inline]
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// The value of a header, matched ignoring case.
+    #[inline]
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        find_header(&self.headers, name)
+    }
+
+    /// All headers in the order they were sent.
+    #[inline]
+    pub fn headers(&self) -> &Vec<(String, Vec<u8>)> {
+        &self.headers
+    }
+
+    /// Write the response as HTTP.
+    pub fn format(&self, w: &mut dyn Write) -> Result<()> {
+        write!(w, "HTTP/1.1 {} {}\r\n", self.status, self.reason)?;
+        write_headers(&self.headers, w)
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<RESPONSE> {} {}", self.status, self.reason)
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+
+    #[test]
+    fn accept_key() {
+        assert_eq!(hash_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+        assert_eq!(encode_base64(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn request_response() {
+        let url = Url::parse("ws://127.0.0.1:3012/chat?room=1").unwrap();
+        let req = Request::from_url(&url).unwrap();
+        let mut buf = Vec::new();
+        req.format(&mut buf).unwrap();
+        buf.extend(b"\x81");
+
+        let (parsed, size) = Request::parse(&buf).unwrap().unwrap();
+        assert_eq!(size, buf.len() - 1);
+        assert_eq!(parsed.path(), "/chat?room=1");
+        assert_eq!(parsed.header("host"), Some(&b"127.0.0.1:3012"[..]));
+        parsed.validate().unwrap();
+
+        let res = Response::from_request(&parsed).unwrap();
+        let mut buf = Vec::new();
+        res.format(&mut buf).unwrap();
+        let (parsed, _) = Response::parse(&buf).unwrap().unwrap();
+        assert_eq!(parsed.status(), 101);
+        parsed.validate(req.key().unwrap()).unwrap();
+        assert!(parsed.validate(b"other").is_err());
+
+        let mut partial = Response::parse(&buf).unwrap().unwrap().0;
+        partial.headers.retain(|&(ref name, _)| !name.eq_ignore_ascii_case("Connection"));
+        assert!(partial.validate(req.key().unwrap()).is_err());
+        assert!(Request::parse(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap().is_none());
+    }
+}
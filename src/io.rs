@@ -1,14 +1,27 @@
-use super::Settings;
+use super::{RunMode, Settings};
+use codec::NewCodec;
 use communication::{Sender, Signal, Command};
-use connection::Connection;
+use connection::{Connection, Endpoint, Timer, TimerRequest};
 use factory::Factory;
+use handler::Handler as SocketHandler;
 use mio;
 use mio::{Token, Ready, Poll, PollOpt};
 use mio::tcp::{TcpListener, TcpStream};
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
 use result::{Result, Error, Kind};
+#[cfg(all(unix, feature = "signals"))]
+use signals::{self, SignalPipe};
 use std::borrow::Borrow;
 use std::io::{ErrorKind, Error as IoError};
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "async")]
+use std::task::Waker;
 use std::time::Duration;
 use std::usize;
 use url::Url;
@@ -17,6 +30,13 @@ const QUEUE: Token = Token(usize::MAX - 3); //接受数据方监听的fd,
 const TIMER: Token = Token(usize::MAX - 4);
 pub const ALL: Token = Token(usize::MAX - 5);
 const SYSTEM: Token = Token(usize::MAX - 6);
+const RECONNECT: Token = Token(usize::MAX - 7);
+#[cfg(all(unix, feature = "signals"))]
+const SIGNALS: Token = Token(usize::MAX - 8);
+// Broadcasts forwarded from another event loop of the same pool.
+const LOCAL: Token = Token(usize::MAX - 9);
+// Tokens of listening sockets count down from here, far away from the connection tokens.
+const LISTENERS: usize = usize::MAX - 64;
 
 type Conn<F> = Connection<<F as Factory>::Handler>;
 
@@ -31,48 +51,172 @@ const CONNECTION_REFUSED: i32 = 111;
 #[cfg(windows)]
 const CONNECTION_REFUSED: i32 = 61;
 
-fn url_to_addrs(url: &String) -> Result<Vec<SocketAddr>> {
-    //    let host = url.host_str();
+// Split a url into the host and port to connect to.
+// Accepts `tcp://`, `ws://` and `wss://` urls as well as a plain `host:port`, the WebSocket
+// schemes fall back to their default ports. `unix://` urls are recognized but refused with an
+// error: connections are mio TCP streams throughout, and unix domain sockets would need a
+// stream type of their own.
+fn url_host_port(url: &str) -> Result<(String, u16)> {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        // plain host:port, e.g. 127.0.0.1:3012 or [::1]:3012
+        Err(_) => return split_host_port(url),
+    };
 
-    //    if host.is_none() || (url.scheme() != "ws" && url.scheme() != "wss") {
-    //        return Err(Error::new(Kind::Internal, format!("Not a valid socket url: {}", url)));
-    //    }
-    //    let host = host.unwrap();
-    //
-    //    let port = url.port_or_known_default().unwrap_or(80);
-    //    let mut addrs = try!((&host[..], port).to_socket_addrs()).collect::<Vec<SocketAddr>>();
-    //    addrs.dedup();
+    let default_port = match parsed.scheme() {
+        "tcp" => None,
+        "ws" => Some(80),
+        "wss" => Some(443),
+        "unix" => {
+            return Err(Error::new(Kind::Internal, format!("Unix domain sockets are not supported, unable to connect to {}", url)));
+        }
+        // a host name without a scheme, e.g. localhost:3012
+        _ if !parsed.has_host() => return split_host_port(url),
+        scheme => return Err(Error::new(Kind::Internal, format!("Unsupported scheme {} in url {}", scheme, url))),
+    };
+
+    let host = match parsed.host_str() {
+        Some(host) if !host.is_empty() => host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        _ => return Err(Error::new(Kind::Internal, format!("Missing host in url {}", url))),
+    };
+    match parsed.port().or(default_port) {
+        Some(port) => Ok((host, port)),
+        None => Err(Error::new(Kind::Internal, format!("Missing port in url {}", url))),
+    }
+}
 
-    //TODO TCP address
-    let addrs = Vec::from(url.to_socket_addrs().unwrap().as_slice());
+fn split_host_port(url: &str) -> Result<(String, u16)> {
+    let idx = url.rfind(':').ok_or_else(|| Error::new(Kind::Internal, format!("Missing port in address {}", url)))?;
+    let host = url[..idx].trim_matches(|c| c == '[' || c == ']');
+    if host.is_empty() {
+        return Err(Error::new(Kind::Internal, format!("Missing host in address {}", url)));
+    }
+    let port = url[idx + 1..].parse().map_err(|_| Error::new(Kind::Internal, format!("Invalid port in address {}", url)))?;
+    Ok((host.to_string(), port))
+}
+
+fn url_to_addrs(url: &str) -> Result<Vec<SocketAddr>> {
+    let (host, port) = url_host_port(url)?;
+    let mut addrs = match (&host[..], port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect::<Vec<SocketAddr>>(),
+        Err(err) => return Err(Error::new(Kind::Io(err), format!("Unable to resolve {}", url))),
+    };
+    addrs.dedup();
+    if addrs.is_empty() {
+        return Err(Error::new(Kind::Internal, format!("No addresses found for {}", url)));
+    }
     Ok(addrs)
 }
 
+// The host a url points to, which a TLS server has to present a certificate for.
+#[cfg(feature = "ssl")]
+fn url_domain(url: &str) -> String {
+    match url_host_port(url) {
+        Ok((host, _)) => host,
+        Err(_) => url.into(),
+    }
+}
+
 enum State {
     Active,
+    // shutting down gracefully, connections are closing and flushing what they have left
+    Draining(mio::timer::Timeout),
     Inactive,
 }
 
 impl State {
     fn is_active(&self) -> bool {
         match *self {
-            State::Active => true,
+            State::Active | State::Draining(_) => true,
             State::Inactive => false,
         }
     }
+
+    fn is_draining(&self) -> bool {
+        matches!(*self, State::Draining(_))
+    }
+}
+
+/// A connection that was dropped because a graceful shutdown reached its deadline.
+#[derive(Debug, Clone)]
+pub struct CutOff {
+    /// The id of the connection as passed to `Handler::on_open`.
+    pub connection_id: u32,
+    /// The address of the other endpoint, if it is still known.
+    pub peer_addr: Option<SocketAddr>,
+    /// Whether the connection was accepted or made to a url.
+    pub endpoint: Endpoint,
+    /// The number of bytes that were never written, 0 if only the close handshake was unfinished.
+    pub unsent: usize,
+}
+
+/// Another event loop of the same pool, broadcasts are passed on to it while it runs.
+#[derive(Clone)]
+pub struct Peer {
+    sender: Sender,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Wakes the tasks waiting for room in the command queue once the event loop took commands out.
+#[cfg(feature = "async")]
+#[derive(Default)]
+pub struct QueueRoom {
+    wakers: Mutex<Vec<Waker>>,
+}
+
+#[cfg(feature = "async")]
+impl QueueRoom {
+    pub fn register(&self, waker: &Waker) {
+        if let Ok(mut wakers) = self.wakers.lock() {
+            if !wakers.iter().any(|known| known.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+        }
+    }
+
+    pub fn wake(&self) {
+        let wakers = match self.wakers.lock() {
+            Ok(mut wakers) => ::std::mem::take(&mut *wakers),
+            Err(_) => return,
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Timeout {
     connection: Token,
-    event: Token,
+    // the connection the timeout was set for, its token may belong to a newer one by now
+    connection_id: u32,
+    event: TimeoutEvent,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TimeoutEvent {
+    // a token scheduled through `Sender::timeout`, passed on to the handler
+    Handler(Token),
+    // one of the timers a connection runs for itself
+    Connection(Timer),
+    // the pending reconnect with the given index
+    Reconnect(Token),
+    // the deadline of a graceful shutdown
+    Deadline,
+}
+
+struct Listener {
+    token: Token,
+    socket: TcpListener,
+    addr: SocketAddr,
 }
 
 pub struct Handler<F>
 where
     F: Factory,
 {
-    listener: Option<TcpListener>,
+    listeners: Vec<Listener>,
+    next_listener: usize,
     connections: Slab<Conn<F>>,
     factory: F,
     settings: Settings,
@@ -81,6 +225,19 @@ where
     queue_rx: mio::channel::Receiver<Command>,
     timer: mio::timer::Timer<Timeout>,
     next_connection_id: u32,
+    connection_id_step: u32,
+    codec: NewCodec,
+    //等待重连的url和重连次数
+    reconnects: Slab<(String, u32)>,
+    cut_off: Vec<CutOff>,
+    // the other event loops of a pool, broadcasts are passed on to them
+    peers: Vec<Peer>,
+    // set once `run` returned, the other loops of a pool stop forwarding to this one
+    stopped: Arc<AtomicBool>,
+    #[cfg(feature = "async")]
+    queue_room: Arc<QueueRoom>,
+    #[cfg(all(unix, feature = "signals"))]
+    signals: Option<SignalPipe>,
 }
 
 
@@ -88,7 +245,7 @@ impl<F> Handler<F>
 where
     F: Factory,
 {
-    pub fn new(factory: F, settings: Settings) -> Handler<F> {
+    pub fn new(factory: F, settings: Settings, codec: NewCodec) -> Handler<F> {
         let (tx, rx) = mio::channel::sync_channel(settings.max_connections * settings.queue_size);
         let timer = mio::timer::Builder::default()
             .tick_duration(Duration::from_millis(TIMER_TICK_MILLIS))
@@ -96,7 +253,8 @@ where
             .capacity(TIMER_CAPACITY)
             .build();
         Handler {
-            listener: None,
+            listeners: Vec::new(),
+            next_listener: 0,
             connections: Slab::with_capacity(settings.max_connections),
             factory: factory,
             settings: settings,
@@ -105,6 +263,16 @@ where
             queue_rx: rx,
             timer: timer,
             next_connection_id: 0,
+            connection_id_step: 1,
+            codec,
+            reconnects: Slab::with_capacity(settings.max_connections),
+            cut_off: Vec::new(),
+            peers: Vec::new(),
+            stopped: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "async")]
+            queue_room: Arc::new(QueueRoom::default()),
+            #[cfg(all(unix, feature = "signals"))]
+            signals: None,
         }
     }
 
@@ -116,33 +284,108 @@ where
         self.queue_tx.clone()
     }
 
-    pub fn listen(&mut self, poll: &mut Poll, addr: &SocketAddr) -> Result<&mut Handler<F>> {
-        debug_assert!(self.listener.is_none(), "Attempted to listen for connections from two addresses on the same socket.");
+    pub fn factory(&self) -> &F {
+        &self.factory
+    }
+
+    /// How another event loop of the same pool forwards its broadcasts to this one.
+    pub fn peer(&self) -> Peer {
+        Peer {
+            sender: Sender::new(LOCAL, self.queue_tx.clone(), 0),
+            stopped: self.stopped.clone(),
+        }
+    }
+
+    /// Where async senders wait for room in the command queue.
+    #[cfg(feature = "async")]
+    pub fn queue_room(&self) -> Arc<QueueRoom> {
+        self.queue_room.clone()
+    }
+
+    pub fn add_peer(&mut self, peer: Peer) {
+        self.peers.push(peer);
+    }
+
+    /// Hand out the connection ids `first`, `first + step`, ... so that the event loops of a
+    /// pool never share an id.
+    pub fn set_connection_ids(&mut self, first: u32, step: u32) {
+        self.next_connection_id = first;
+        self.connection_id_step = step.max(1);
+    }
 
-        let tcp = TcpListener::bind(addr)?;
-        // TODO: consider net2 in order to set reuse_addr
-        poll.register(&tcp, ALL, Ready::readable(), PollOpt::level())?;
-        self.listener = Some(tcp);
+    pub fn listen(&mut self, poll: &mut Poll, addr: &SocketAddr) -> Result<&mut Handler<F>> {
+        let tcp = self.bind(addr)?;
+        let token = Token(LISTENERS - self.next_listener);
+        poll.register(&tcp, token, Ready::readable(), PollOpt::level())?;
+        self.next_listener += 1;
+        let addr = tcp.local_addr()?;
+        self.listeners.push(Listener { token, socket: tcp, addr });
         Ok(self)
     }
 
+    fn bind(&self, addr: &SocketAddr) -> Result<TcpListener> {
+        let builder = match *addr {
+            SocketAddr::V4(..) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(..) => TcpBuilder::new_v6()?,
+        };
+        #[cfg(unix)]
+        {
+            builder.reuse_address(true)?;
+            if self.settings.reuse_port {
+                builder.reuse_port(true)?;
+            }
+        }
+        let listener = builder.bind(addr)?.listen(1024)?;
+        Ok(TcpListener::from_std(listener)?)
+    }
+
+    fn start_listening(&mut self, poll: &mut Poll, addr: &SocketAddr) {
+        match self.listen(poll, addr) {
+            Ok(_) => info!("Listening for new connections on {}.", self.listeners.last().unwrap().addr),
+            Err(err) => {
+                if self.settings.panic_on_new_connection {
+                    panic!("Unable to listen on {}: {:?}", addr, err);
+                }
+                error!("Unable to listen on {}: {:?}", addr, err);
+            }
+        }
+    }
+
+    fn stop_listening(&mut self, poll: &mut Poll, addr: &SocketAddr) {
+        if let Some(idx) = self.listeners.iter().position(|listener| listener.addr == *addr) {
+            let listener = self.listeners.remove(idx);
+            if let Err(err) = poll.deregister(&listener.socket) {
+                error!("Unable to deregister listener on {}: {:?}", addr, err);
+            }
+            info!("Stopped listening for new connections on {}.", addr);
+        } else {
+            debug!("Not listening on {}, nothing to stop.", addr);
+        }
+    }
+
+    /// The address of the first listener.
     pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
-        if let Some(ref listener) = self.listener {
-            listener.local_addr()
+        if let Some(listener) = self.listeners.first() {
+            Ok(listener.addr)
         } else {
             Err(IoError::new(ErrorKind::NotFound, "Not a listening socket"))
         }
     }
 
+    /// The addresses of all listeners in the order they were bound.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().map(|listener| listener.addr).collect()
+    }
+
 
-    pub fn connect(&mut self, poll: &mut Poll, url: String) -> Result<()> {
+    pub fn connect(&mut self, poll: &mut Poll, url: String) -> Result<Token> {
         let settings = self.settings;
 
         let (tok, addresses) = {
-            let (tok, entry, connection_id, handler) = if let Some(entry) = self.connections.vacant_entry() {
+            let (tok, entry, connection_id, mut handler) = if let Some(entry) = self.connections.vacant_entry() {
                 let tok = entry.index();
                 let connection_id = self.next_connection_id;
-                self.next_connection_id = self.next_connection_id.wrapping_add(1);
+                self.next_connection_id = self.next_connection_id.wrapping_add(self.connection_id_step);
                 (tok, entry, connection_id, self.factory.client_connected(Sender::new(tok, self.queue_tx.clone(), connection_id)))
             } else {
                 return Err(Error::new(Kind::Capacity, "Unable to add another connection to the event loop."));
@@ -151,38 +394,62 @@ where
             let mut addresses = match url_to_addrs(&url) {
                 Ok(addresses) => addresses,
                 Err(err) => {
+                    let msg = err.to_string();
+                    handler.on_connect_failed(&url, err);
                     self.factory.connection_lost(handler);
-                    return Err(err);
+                    return Err(Error::new(Kind::Internal, format!("Unable to connect to {}: {}", url, msg)));
                 }
             };
 
+            let mut last_err = None;
             loop {
                 if let Some(addr) = addresses.pop() {
-                    if let Ok(sock) = TcpStream::connect(&addr) {
-                        if settings.tcp_nodelay {
-                            sock.set_nodelay(true)?
+                    match TcpStream::connect(&addr) {
+                        Ok(sock) => {
+                            if settings.tcp_nodelay {
+                                sock.set_nodelay(true)?
+                            }
+                            // the connection is opened once the socket becomes writable
+                            entry.insert(Connection::new(tok, sock, handler, settings, connection_id, (self.codec)()));
+                            break;
                         }
-                        let mut conn = Connection::new(tok, sock, handler, settings, connection_id);
-                        //TODO connected to do on_open() function
-                        conn.open();
-                        entry.insert(conn);
-                        break;
+                        Err(err) => last_err = Some(err),
                     }
                 } else {
+                    let err = match last_err {
+                        Some(err) => Error::from(err),
+                        None => Error::new(Kind::Internal, format!("Unable to obtain any socket address for {}", url)),
+                    };
+                    let msg = err.to_string();
+                    handler.on_connect_failed(&url, err);
                     self.factory.connection_lost(handler);
-                    return Err(Error::new(Kind::Internal, format!("Unable to obtain any socket address for {}", url)));
+                    return Err(Error::new(Kind::Internal, format!("Unable to connect to {}: {}", url, msg)));
                 }
             }
 
             (tok, addresses)
         };
 
-        if let Err(error) = self.connections[tok].as_client(url, addresses) {
+        #[cfg(feature = "ssl")]
+        let connector = self.factory.ssl_connector(&url);
+
+        if let Err(error) = self.connections[tok].as_client(url.clone(), addresses) {
             let handler = self.connections.remove(tok).unwrap().consume();
             self.factory.connection_lost(handler);
             return Err(error);
         }
 
+        #[cfg(feature = "ssl")]
+        {
+            if let Some(connector) = connector {
+                if let Err(error) = self.connections[tok].as_tls_client(connector, url_domain(&url)) {
+                    let handler = self.connections.remove(tok).unwrap().consume();
+                    self.factory.connection_lost(handler);
+                    return Err(error);
+                }
+            }
+        }
+
         //register socket event
         poll.register(self.connections[tok].socket(), self.connections[tok].token(), self.connections[tok].events(), PollOpt::edge() | PollOpt::oneshot())
             .map_err(Error::from)
@@ -191,11 +458,75 @@ where
                          let handler = self.connections.remove(tok).unwrap().consume();
                          self.factory.connection_lost(handler);
                          Err(err)
-                     })
+                     })?;
+        self.arm_timers(tok);
+        Ok(tok)
     }
 
+    // Wait for the delay of the reconnect policy before dialing the url again.
+    fn schedule_reconnect(&mut self, url: String, attempt: u32) {
+        if !self.state.is_active() || self.state.is_draining() {
+            return;
+        }
+        let delay = match self.settings.reconnect.delay(attempt) {
+            Some(delay) => delay,
+            None => {
+                if attempt > 1 {
+                    info!("Giving up on reconnecting to {} after {} attempts.", url, attempt - 1);
+                }
+                return;
+            }
+        };
 
-    pub fn accept(&mut self, poll: &mut Poll, sock: TcpStream) -> Result<()> {
+        let index = if let Some(entry) = self.reconnects.vacant_entry() {
+            let index = entry.index();
+            entry.insert((url, attempt));
+            index
+        } else {
+            error!("Unable to reconnect to {}, too many pending reconnects.", url);
+            return;
+        };
+
+        debug!("Reconnecting to {} in {}ms.", self.reconnects[index].0, delay);
+        if let Err(err) = self.timer.set_timeout(Duration::from_millis(delay), Timeout { connection: RECONNECT, connection_id: 0, event: TimeoutEvent::Reconnect(index) }) {
+            error!("Unable to schedule reconnect: {:?}", err);
+            self.reconnects.remove(index);
+        }
+    }
+
+    fn reconnect(&mut self, poll: &mut Poll, index: Token) {
+        let (url, attempt) = match self.reconnects.remove(index) {
+            Some(reconnect) => reconnect,
+            None => return,
+        };
+        if !self.factory.on_reconnect(&url, attempt) {
+            debug!("Factory gave up on reconnecting to {}.", url);
+            return;
+        }
+
+        info!("Reconnecting to {}, attempt {}.", url, attempt);
+        match self.connect(poll, url.clone()) {
+            Ok(tok) => self.connections[tok].set_attempt(attempt),
+            Err(err) => {
+                error!("Unable to reconnect to {}: {:?}", url, err);
+                self.schedule_reconnect(url, attempt + 1);
+            }
+        }
+    }
+
+    // Hand the handler of a lost connection back to the factory, client connections are
+    // dialed again if the reconnect policy allows it.
+    fn remove_connection(&mut self, token: Token) {
+        let conn = self.connections.remove(token).unwrap();
+        let redial = conn.redial();
+        self.factory.connection_lost(conn.consume());
+        if let Some((url, attempt)) = redial {
+            self.schedule_reconnect(url, attempt);
+        }
+    }
+
+
+    pub fn accept(&mut self, poll: &mut Poll, sock: TcpStream, listener: &SocketAddr) -> Result<()> {
         let factory = &mut self.factory;
         let settings = self.settings;
 
@@ -203,44 +534,67 @@ where
             sock.set_nodelay(true)?
         }
 
+        #[cfg(feature = "ssl")]
+        let acceptor = factory.ssl_acceptor(&sock.peer_addr()?);
+
         let tok = {
             if let Some(entry) = self.connections.vacant_entry() {
                 let tok = entry.index();
                 let connection_id = self.next_connection_id;
-                self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                let handler = factory.server_connected(Sender::new(tok, self.queue_tx.clone(), connection_id));
-                entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
+                self.next_connection_id = self.next_connection_id.wrapping_add(self.connection_id_step);
+                let handler = factory.listener_connected(Sender::new(tok, self.queue_tx.clone(), connection_id), listener);
+                entry.insert(Connection::new(tok, sock, handler, settings, connection_id, (self.codec)()));
                 tok
             } else {
                 return Err(Error::new(Kind::Capacity, "Unable to add another connection to the event loop."));
             }
         };
 
-        let conn = &mut self.connections[tok];
-        conn.as_server()?; //监听可读
-
-        let ret: Result<()> = poll.register(conn.socket(), conn.token(), conn.events(), PollOpt::edge() | PollOpt::oneshot())
-                                  .map_err(|err| Error::from(err))
-                                  .or_else(|err| {
-                                               error!("Encountered error while trying to build socket connection: {}", err);
-                                               conn.error(err);
-                                               if settings.panic_on_new_connection {
-                                                   panic!("Encountered error while trying to build socket connection.");
-                                               }
-                                               Ok(())
-                                           });
-        ret?;
-
-        //open connection on_open() to change state
-        trace!("acecept new connection");
-        conn.open().map_err(Error::from).or_else(|err| {
-                                                     error!("Encountered error while trying to build socket connection: {}", err);
-                                                     conn.error(err);
-                                                     if settings.panic_on_new_connection {
-                                                         panic!("Encountered error while trying to build socket connection.");
-                                                     }
-                                                     Ok(())
-                                                 })
+        let active = {
+            let conn = &mut self.connections[tok];
+            conn.as_server()?; //监听可读
+
+            #[cfg(feature = "ssl")]
+            {
+                if let Some(acceptor) = acceptor {
+                    if let Err(err) = conn.as_tls_server(&acceptor) {
+                        let handler = self.connections.remove(tok).unwrap().consume();
+                        factory.connection_lost(handler);
+                        return Err(err);
+                    }
+                }
+            }
+
+            let ret: Result<()> = poll.register(conn.socket(), conn.token(), conn.events(), PollOpt::edge() | PollOpt::oneshot())
+                                      .map_err(|err| Error::from(err))
+                                      .or_else(|err| {
+                                                   error!("Encountered error while trying to build socket connection: {}", err);
+                                                   conn.error(err);
+                                                   if settings.panic_on_new_connection {
+                                                       panic!("Encountered error while trying to build socket connection.");
+                                                   }
+                                                   Ok(())
+                                               });
+            ret?;
+
+            //open connection on_open() to change state, TLS connections are opened once negotiated
+            trace!("acecept new connection");
+            if conn.is_negotiating() {
+                trace!("Waiting for TLS negotiation before opening connection.");
+            } else if let Err(err) = conn.open() {
+                error!("Encountered error while opening socket connection: {}", err);
+                conn.error(err);
+                if settings.panic_on_new_connection {
+                    panic!("Encountered error while opening socket connection.");
+                }
+            }
+
+            conn.events().is_readable() || conn.events().is_writable()
+        };
+
+        // on_open may have closed the connection
+        self.check_active(poll, active, tok);
+        Ok(())
     }
 
     pub fn run(&mut self, poll: &mut Poll) -> Result<()> {
@@ -248,15 +602,68 @@ where
         poll.register(&self.queue_rx, QUEUE, Ready::readable(), PollOpt::edge() | PollOpt::oneshot())?;
         poll.register(&self.timer, TIMER, Ready::readable(), PollOpt::edge())?;
 
+        #[cfg(all(unix, feature = "signals"))]
+        {
+            if self.settings.handle_signals {
+                let pipe = SignalPipe::new()?;
+                pipe.register(poll, SIGNALS)?;
+                self.signals = Some(pipe);
+            }
+        }
+
         self.state = State::Active;
+        self.cut_off.clear();
+        self.stopped.store(false, Ordering::SeqCst);
         let result = self.event_loop(poll);
+        self.stopped.store(true, Ordering::SeqCst);
 
         //close XnetSocket after clean's work
         self.state = State::Inactive;
+        #[cfg(all(unix, feature = "signals"))]
+        let result = match self.signals.take() {
+            Some(pipe) => result.and(pipe.deregister(poll).map_err(Error::from)),
+            None => result,
+        };
         result.and(poll.deregister(&self.timer).map_err(|e| Error::from(e)))
               .and(poll.deregister(&self.queue_rx).map_err(|e| Error::from(e)))
     }
 
+    #[cfg(all(unix, feature = "signals"))]
+    fn handle_signals(&mut self, poll: &mut Poll) {
+        let received = match self.signals {
+            Some(ref pipe) => pipe.received(),
+            None => return,
+        };
+        for sig in received {
+            match sig {
+                signals::SIGINT | signals::SIGTERM => {
+                    info!("Received signal {}, shutting down.", sig);
+                    if self.state.is_draining() || self.settings.signal_shutdown_deadline == 0 {
+                        self.shutdown();
+                    } else {
+                        let deadline = self.settings.signal_shutdown_deadline;
+                        self.graceful_shutdown(poll, deadline);
+                    }
+                }
+                signals::SIGHUP => {
+                    info!("Received SIGHUP.");
+                    self.factory.on_hangup();
+                }
+                _ => debug!("Ignoring signal {}.", sig),
+            }
+        }
+    }
+
+    #[cfg(all(unix, feature = "signals"))]
+    fn handles_signals(&self) -> bool {
+        self.signals.is_some()
+    }
+
+    #[cfg(not(all(unix, feature = "signals")))]
+    fn handles_signals(&self) -> bool {
+        false
+    }
+
     #[inline]
     fn event_loop(&mut self, poll: &mut Poll) -> Result<()> {
         let mut events = mio::Events::with_capacity(MAX_EVENTS);
@@ -267,7 +674,10 @@ where
                 Ok(nevents) => nevents,
                 Err(err) => {
                     if err.kind() == ErrorKind::Interrupted {
-                        if self.settings.shutdown_on_interrupt {
+                        if self.handles_signals() {
+                            // the signal arrives through the signal pipe
+                            trace!("socket poll interrupted by a signal.");
+                        } else if self.settings.shutdown_on_interrupt {
                             error!("socket shutting down for interrupt.");
                             self.state = State::Inactive;
                         } else {
@@ -309,42 +719,156 @@ where
         }
     }
 
+    // Stop accepting and dialing, close every connection and keep running until they flushed
+    // what they have left or the deadline in milliseconds passed.
+    fn graceful_shutdown(&mut self, poll: &mut Poll, deadline: u64) {
+        if !self.state.is_active() || self.state.is_draining() {
+            return;
+        }
+        debug!("Received graceful shutdown signal, draining {} connections.", self.connections.len());
+        let timeout = match self.timer.set_timeout(Duration::from_millis(deadline), Timeout { connection: SYSTEM, connection_id: 0, event: TimeoutEvent::Deadline }) {
+            Ok(timeout) => timeout,
+            Err(err) => {
+                error!("Unable to schedule shutdown deadline, shutting down now: {:?}", err);
+                return self.shutdown();
+            }
+        };
+        self.state = State::Draining(timeout);
+
+        for listener in self.listeners.drain(..) {
+            if let Err(err) = poll.deregister(&listener.socket) {
+                error!("Unable to deregister listener on {}: {:?}", listener.addr, err);
+            }
+        }
+        self.reconnects.clear();
+
+        let tokens = self.connections.iter().map(|conn| conn.token()).collect::<Vec<Token>>();
+        for token in tokens {
+            let active = {
+                let conn = &mut self.connections[token];
+                conn.shutdown();
+                conn.events().is_readable() || conn.events().is_writable()
+            };
+            self.check_active(poll, active, token);
+        }
+
+        self.factory.on_shutdown();
+        if self.settings.panic_on_shutdown {
+            panic!("Panicking on shutdown as per setting.")
+        }
+    }
+
+    // The deadline of a graceful shutdown passed, drop whatever is still connected.
+    fn cut_off_connections(&mut self) {
+        if !self.state.is_draining() {
+            return;
+        }
+        let tokens = self.connections.iter().map(|conn| conn.token()).collect::<Vec<Token>>();
+        for token in tokens {
+            let mut conn = self.connections.remove(token).unwrap();
+            warn!("Cutting off connection {} with {} bytes unsent.", conn.connection_id(), conn.unsent());
+            self.cut_off.push(CutOff {
+                connection_id: conn.connection_id(),
+                peer_addr: conn.socket().peer_addr().ok(),
+                endpoint: conn.endpoint().clone(),
+                unsent: conn.unsent(),
+            });
+            conn.disconnect();
+            self.factory.connection_lost(conn.consume());
+        }
+        self.state = State::Inactive;
+    }
+
+    /// The connections dropped by the last graceful shutdown.
+    pub fn cut_off(&self) -> &[CutOff] {
+        &self.cut_off
+    }
+
+    // Carry out the timer requests of a connection on the timer of the event loop. Going through
+    // the queue instead could block the loop on its own full queue.
+    fn arm_timers(&mut self, token: Token) {
+        loop {
+            let (connection_id, requests) = match self.connections.get_mut(token) {
+                Some(conn) => (conn.connection_id(), conn.take_timers()),
+                None => return,
+            };
+            if requests.is_empty() {
+                return;
+            }
+            for request in requests {
+                match request {
+                    TimerRequest::Arm(timer, delay) => {
+                        let timeout = Timeout { connection: token, connection_id, event: TimeoutEvent::Connection(timer) };
+                        match self.timer.set_timeout(Duration::from_millis(delay), timeout) {
+                            Ok(timeout) => self.connections[token].new_timer(timer, timeout),
+                            Err(err) => {
+                                if self.settings.panic_on_timeout {
+                                    panic!("Unable to schedule {:?} timer: {:?}", timer, err);
+                                }
+                                error!("Unable to schedule {:?} timer: {:?}", timer, err);
+                            }
+                        }
+                    }
+                    TimerRequest::Cancel(timeout) => {
+                        self.timer.cancel_timeout(&timeout);
+                    }
+                }
+            }
+        }
+    }
+
     #[inline]
     fn check_active(&mut self, poll: &mut Poll, active: bool, token: Token) {
+        self.arm_timers(token);
         if !active {
             if let Ok(addr) = self.connections[token].socket().peer_addr() {
                 debug!("socket connection to {} disconnected.", addr);
             } else {
                 trace!("socket connection to token={:?} disconnected.", token);
             }
-            let handler = self.connections.remove(token).unwrap().consume();
-            self.factory.connection_lost(handler);
+            self.remove_connection(token);
         } else {
             self.schedule(poll, &self.connections[token])
                 .or_else(|err| {
                              // This will be an io error, so disconnect will already be called
                              self.connections[token].error(Error::from(err));
-                             let handler = self.connections.remove(token).unwrap().consume();
-                             self.factory.connection_lost(handler);
+                             self.remove_connection(token);
                              Ok::<(), Error>(())
                          })
                 .unwrap()
         }
     }
 
+    // Whether the loop stops once its connections are gone, a listening loop waits for more
+    // unless told otherwise.
+    #[inline]
+    fn stops_when_idle(&self) -> bool {
+        match self.settings.run_mode {
+            RunMode::Auto => self.listeners.is_empty(),
+            RunMode::UntilIdle => true,
+            RunMode::UntilShutdown => false,
+        }
+    }
+
     #[inline]
-    fn is_client(&self) -> bool {
-        self.listener.is_none()
+    fn is_listener(&self, token: Token) -> bool {
+        self.listeners.iter().any(|listener| listener.token == token)
     }
 
     #[inline]
     fn check_count(&mut self) {
         trace!("Active connections {:?}", self.connections.len());
-        if self.connections.len() == 0 {
+        if self.connections.is_empty() {
             if !self.state.is_active() {
                 debug!("Shutting down socket server.");
 
-            } else if self.is_client() {
+            } else if self.state.is_draining() {
+                debug!("All connections drained, shutting down.");
+                if let State::Draining(ref timeout) = self.state {
+                    self.timer.cancel_timeout(timeout);
+                }
+                self.state = State::Inactive;
+            } else if self.stops_when_idle() && self.reconnects.is_empty() {
                 debug!("Shutting down socket client.");
                 self.factory.on_shutdown();
                 self.state = State::Inactive;
@@ -358,12 +882,16 @@ where
                 debug_assert!(false, "System token used for io event. This is a bug!");
                 error!("System token used for io event. This is a bug!");
             }
-            ALL => {
+            token if self.is_listener(token) => {
                 if events.is_readable() {
-                    match self.listener.as_ref().expect("No listener provided for server socket connections").accept() {
+                    let (accepted, listener) = {
+                        let listener = self.listeners.iter().find(|listener| listener.token == token).unwrap();
+                        (listener.socket.accept(), listener.addr)
+                    };
+                    match accepted {
                         Ok((sock, addr)) => {
-                            info!("Accepted a new tcp connection from {}.", addr);
-                            if let Err(err) = self.accept(poll, sock) {
+                            info!("Accepted a new tcp connection from {} on {}.", addr, listener);
+                            if let Err(err) = self.accept(poll, sock, &listener) {
                                 error!("Unable to build socket connection {:?}", err);
                                 if self.settings.panic_on_new_connection {
                                     panic!("Unable to build socket connection {:?}", err);
@@ -374,6 +902,8 @@ where
                     }
                 }
             }
+            #[cfg(all(unix, feature = "signals"))]
+            SIGNALS => self.handle_signals(poll),
             TIMER => {
                 while let Some(t) = self.timer.poll() {
                     self.handle_timeout(poll, t);
@@ -388,6 +918,8 @@ where
                     };
                     break;
                 }
+                #[cfg(feature = "async")]
+                self.queue_room.wake();
                 let _ = poll.reregister(&self.queue_rx, QUEUE, Ready::readable(), PollOpt::edge() | PollOpt::oneshot());
             }
             _ => {
@@ -407,8 +939,7 @@ where
                                                 poll.register(self.connections[token].socket(), self.connections[token].token(), self.connections[token].events(), PollOpt::edge() | PollOpt::oneshot())
                                                     .or_else(|err| {
                                                                  self.connections[token].error(Error::from(err));
-                                                                 let handler = self.connections.remove(token).unwrap().consume();
-                                                                 self.factory.connection_lost(handler);
+                                                                 self.remove_connection(token);
                                                                  Ok::<(), Error>(())
                                                              })
                                                     .unwrap();
@@ -441,8 +972,7 @@ where
                                                 poll.register(self.connections[token].socket(), self.connections[token].token(), self.connections[token].events(), PollOpt::edge() | PollOpt::oneshot())
                                                     .or_else(|err| {
                                                                  self.connections[token].error(Error::from(err));
-                                                                 let handler = self.connections.remove(token).unwrap().consume();
-                                                                 self.factory.connection_lost(handler);
+                                                                 self.remove_connection(token);
                                                                  Ok::<(), Error>(())
                                                              })
                                                     .unwrap();
@@ -469,12 +999,49 @@ where
         }
     }
 
+    // Pass a broadcast on to the connections of the other event loops of the pool. A loop that
+    // is behind misses the broadcast rather than blocking this one, a loop that stopped is
+    // forgotten.
+    fn forward(&mut self, cmd: &Command) {
+        let signal = match cmd.clone().signal() {
+            signal @ Signal::Message(_) |
+            signal @ Signal::Close(..) |
+            signal @ Signal::Ping(_) |
+            signal @ Signal::Pong(_) => signal,
+            _ => return,
+        };
+        self.peers.retain(|peer| {
+            if peer.stopped.load(Ordering::SeqCst) {
+                debug!("Another event loop of the pool stopped, no longer forwarding to it.");
+                return false;
+            }
+            match peer.sender.try_signal(signal.clone()) {
+                Ok(()) => true,
+                Err(mio::channel::TrySendError::Full(_)) => {
+                    warn!("Dropping broadcast for another event loop, its queue is full.");
+                    true
+                }
+                Err(mio::channel::TrySendError::Disconnected(_)) => {
+                    debug!("Another event loop of the pool is gone, no longer forwarding to it.");
+                    false
+                }
+                Err(mio::channel::TrySendError::Io(err)) => {
+                    warn!("Unable to forward broadcast to another event loop: {:?}", err);
+                    true
+                }
+            }
+        });
+    }
+
     fn handle_queue(&mut self, poll: &mut Poll, cmd: Command) {
         match cmd.token() {
             SYSTEM => {
                 // Scaffolding for system events such as internal timeouts
             }
-            ALL => {
+            ALL | LOCAL => {
+                if cmd.token() == ALL {
+                    self.forward(&cmd);
+                }
                 //broadcasting message with type
                 let mut dead = Vec::with_capacity(self.connections.len());
 
@@ -490,12 +1057,32 @@ where
                     Signal::Close(code, reason) => {
                         trace!("Broadcasting close: {:?} - {}", code, reason);
                         for conn in self.connections.iter_mut() {
-                            if let Err(err) = conn.send_close(code, reason.borrow()) {
+                            if let Err(err) = conn.request_close(code, reason.borrow()) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
+                    Signal::Ping(data) => {
+                        trace!("Broadcasting ping");
+                        for conn in self.connections.iter_mut() {
+                            if let Err(err) = conn.send_ping(data.clone()) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
+                    Signal::Pong(data) => {
+                        trace!("Broadcasting pong");
+                        for conn in self.connections.iter_mut() {
+                            if let Err(err) = conn.send_pong(data.clone()) {
                                 dead.push((conn.token(), err))
                             }
                         }
                     }
 
+                    Signal::Connect(ref url) if self.state.is_draining() => {
+                        debug!("Not connecting to {} while shutting down.", url);
+                        return;
+                    }
                     Signal::Connect(url) => {
                         if let Err(err) = self.connect(poll, url.clone()) {
                             if self.settings.panic_on_new_connection {
@@ -505,10 +1092,26 @@ where
                         }
                         return;
                     }
+                    Signal::Listen(addr) if self.state.is_draining() => {
+                        debug!("Not listening on {} while shutting down.", addr);
+                        return;
+                    }
+                    Signal::Listen(addr) => {
+                        self.start_listening(poll, &addr);
+                        return;
+                    }
+                    Signal::StopListening(addr) => {
+                        self.stop_listening(poll, &addr);
+                        return;
+                    }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::GracefulShutdown(deadline) => {
+                        self.graceful_shutdown(poll, deadline);
+                        return;
+                    }
                     Signal::Timeout { delay, token: event } => {
                         match self.timer
-                                    .set_timeout(Duration::from_millis(delay), Timeout { connection: ALL, event: event })
+                                    .set_timeout(Duration::from_millis(delay), Timeout { connection: ALL, connection_id: 0, event: TimeoutEvent::Handler(event) })
                                     .map_err(Error::from) {
                             Ok(timeout) => {
                                 for conn in self.connections.iter_mut() {
@@ -532,6 +1135,10 @@ where
                     }
                 }
 
+                let tokens = self.connections.iter().map(|conn| conn.token()).collect::<Vec<Token>>();
+                for token in tokens {
+                    self.arm_timers(token);
+                }
                 for conn in self.connections.iter() {
                     if let Err(err) = self.schedule(poll, conn) {
                         dead.push((conn.token(), err))
@@ -563,7 +1170,7 @@ where
                     Signal::Close(code, reason) => {
                         if let Some(conn) = self.connections.get_mut(token) {
                             if conn.connection_id() == connection_id {
-                                if let Err(err) = conn.send_close(code, reason) {
+                                if let Err(err) = conn.request_close(code, reason) {
                                     conn.error(err)
                                 }
                             } else {
@@ -573,39 +1180,83 @@ where
                             trace!("Connection disconnected while close signal was waiting in the queue.")
                         }
                     }
+                    Signal::Ping(data) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_ping(data) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while ping signal was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while ping signal was waiting in the queue.")
+                        }
+                    }
+                    Signal::Pong(data) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_pong(data) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while pong signal was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while pong signal was waiting in the queue.")
+                        }
+                    }
 
+                    Signal::Connect(ref url) if self.state.is_draining() => {
+                        debug!("Not connecting to {} while shutting down.", url);
+                        return;
+                    }
                     Signal::Connect(url) => {
+                        // the handler of the new connection already heard about the failure
                         if let Err(err) = self.connect(poll, url.clone()) {
-                            if let Some(conn) = self.connections.get_mut(token) {
-                                conn.error(err)
-                            } else {
-                                if self.settings.panic_on_new_connection {
-                                    panic!("Unable to establish connection to {}: {:?}", url, err);
-                                }
-                                error!("Unable to establish connection to {}: {:?}", url, err);
+                            if self.settings.panic_on_new_connection {
+                                panic!("Unable to establish connection to {}: {:?}", url, err);
                             }
+                            error!("Unable to establish connection to {}: {:?}", url, err);
                         }
                         return;
                     }
+                    Signal::Listen(addr) if self.state.is_draining() => {
+                        debug!("Not listening on {} while shutting down.", addr);
+                        return;
+                    }
+                    Signal::Listen(addr) => {
+                        self.start_listening(poll, &addr);
+                        return;
+                    }
+                    Signal::StopListening(addr) => {
+                        self.stop_listening(poll, &addr);
+                        return;
+                    }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::GracefulShutdown(deadline) => {
+                        self.graceful_shutdown(poll, deadline);
+                        return;
+                    }
                     Signal::Timeout { delay, token: event } => {
                         match self.timer
-                                    .set_timeout(Duration::from_millis(delay), Timeout { connection: token, event: event })
+                                    .set_timeout(Duration::from_millis(delay), Timeout { connection: token, connection_id, event: TimeoutEvent::Handler(event) })
                                     .map_err(Error::from) {
                             Ok(timeout) => {
-                                if let Some(conn) = self.connections.get_mut(token) {
+                                // the token may belong to a newer connection by now
+                                if let Some(conn) = self.connections.get_mut(token).filter(|conn| conn.connection_id() == connection_id) {
                                     if let Err(err) = conn.new_timeout(event, timeout) {
                                         conn.error(err)
                                     }
                                 } else {
-                                    trace!("Connection disconnected while pong signal was waiting in the queue.")
+                                    trace!("Connection disconnected while timeout signal was waiting in the queue.")
                                 }
                             }
                             Err(err) => {
                                 if let Some(conn) = self.connections.get_mut(token) {
                                     conn.error(err)
                                 } else {
-                                    trace!("Connection disconnected while pong signal was waiting in the queue.")
+                                    trace!("Connection disconnected while timeout signal was waiting in the queue.")
                                 }
                             }
                         }
@@ -618,6 +1269,7 @@ where
                 }
 
                 if let Some(_) = self.connections.get(token) {
+                    self.arm_timers(token);
                     if let Err(err) = self.schedule(poll, &self.connections[token]) {
                         self.connections[token].error(err)
                     }
@@ -627,19 +1279,486 @@ where
     }
 
 
-    fn handle_timeout(&mut self, poll: &mut Poll, Timeout { connection, event }: Timeout) {
+    fn handle_timeout(&mut self, poll: &mut Poll, Timeout { connection, connection_id, event }: Timeout) {
         let active = {
-            if let Some(conn) = self.connections.get_mut(connection) {
-                if let Err(err) = conn.timeout_triggered(event) {
-                    conn.error(err)
+            let conn = match event {
+                TimeoutEvent::Reconnect(index) => return self.reconnect(poll, index),
+                TimeoutEvent::Deadline => return self.cut_off_connections(),
+                TimeoutEvent::Handler(_) | TimeoutEvent::Connection(_) => {
+                    match self.connections.get_mut(connection) {
+                        Some(conn) if conn.connection_id() == connection_id => conn,
+                        _ => {
+                            trace!("Connection disconnected while timeout was waiting.");
+                            return;
+                        }
+                    }
                 }
-
-                conn.events().is_readable() || conn.events().is_writable()
-            } else {
-                trace!("Connection disconnected while timeout was waiting.");
-                return;
+            };
+            let res = match event {
+                TimeoutEvent::Connection(timer) => conn.timer_triggered(timer),
+                TimeoutEvent::Handler(token) => conn.timeout_triggered(token),
+                _ => Ok(()),
+            };
+            if let Err(err) = res {
+                conn.error(err)
             }
+
+            conn.events().is_readable() || conn.events().is_writable()
         };
         self.check_active(poll, active, connection);
     }
 }
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use codec::{Framed, WebSocket};
+    use handshake::{Handshake, Request, Response};
+    use message::Message;
+    use protocol::CloseCode;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener as StdListener, TcpStream as StdStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Instant;
+    use {Builder, Reconnect, SocketHandle, XnetSocket};
+
+    struct Dial {
+        failed: Arc<Mutex<u32>>,
+    }
+
+    impl SocketHandler for Dial {
+        fn on_connect_failed(&mut self, _: &str, _: Error) {
+            *self.failed.lock().unwrap() += 1;
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Seen {
+        // the peer and local address reported by on_open
+        Open(SocketAddr, SocketAddr),
+        Close,
+        ConnectFailed,
+    }
+
+    // echoes messages and records what its connection went through
+    struct Record {
+        sender: Sender,
+        seen: Arc<Mutex<Vec<Seen>>>,
+    }
+
+    impl SocketHandler for Record {
+        fn on_open(&mut self, shake: Handshake) -> Result<()> {
+            self.seen.lock().unwrap().push(Seen::Open(shake.peer_addr, shake.local_addr));
+            Ok(())
+        }
+
+        fn on_message(&mut self, msg: Message) -> Result<()> {
+            self.sender.send(msg)
+        }
+
+        fn on_close(&mut self, _: CloseCode, _: &str) {
+            self.seen.lock().unwrap().push(Seen::Close);
+        }
+
+        fn on_connect_failed(&mut self, _: &str, _: Error) {
+            self.seen.lock().unwrap().push(Seen::ConnectFailed);
+        }
+    }
+
+    // records the attempts of the reconnect policy
+    struct Redial {
+        seen: Arc<Mutex<Vec<Seen>>>,
+        attempts: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Factory for Redial {
+        type Handler = Record;
+
+        fn connection_made(&mut self, sender: Sender) -> Record {
+            Record { sender, seen: self.seen.clone() }
+        }
+
+        fn on_reconnect(&mut self, _: &str, attempt: u32) -> bool {
+            self.attempts.lock().unwrap().push(attempt);
+            true
+        }
+    }
+
+    fn opened(seen: &Mutex<Vec<Seen>>) -> usize {
+        seen.lock().unwrap().iter().filter(|seen| matches!(seen, Seen::Open(..))).count()
+    }
+
+    // a socket dialing out to a listener of the test, which takes the connection
+    fn client(run_mode: RunMode, seen: &Arc<Mutex<Vec<Seen>>>) -> (SocketHandle<impl Factory<Handler = Record>>, StdListener) {
+        let listener = StdListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings { run_mode, ..Settings::default() };
+        let factory_seen = seen.clone();
+        let mut socket = Builder::new()
+            .with_settings(settings)
+            .build(move |sender| Record { sender, seen: factory_seen.clone() })
+            .unwrap();
+        socket.connect(format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        (socket.spawn().unwrap(), listener)
+    }
+
+    fn join_within<F>(handle: SocketHandle<F>) -> XnetSocket<F>
+    where
+        F: Factory + Send + 'static,
+        F::Handler: Send,
+    {
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || done_tx.send(handle.join()).unwrap());
+        done_rx.recv_timeout(Duration::from_secs(5)).expect("The event loop did not stop.").unwrap()
+    }
+
+    // a loop speaking Framed, which keeps closing connections until the other endpoint answers
+    fn framed_server(seen: &Arc<Mutex<Vec<Seen>>>) -> SocketHandle<impl Factory<Handler = Record>> {
+        let factory_seen = seen.clone();
+        Builder::new()
+            .with_codec(Framed::default())
+            .build(move |sender| Record { sender, seen: factory_seen.clone() })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .spawn()
+            .unwrap()
+    }
+
+    fn accept_within(listener: &StdListener) -> StdStream {
+        listener.set_nonblocking(true).unwrap();
+        let start = Instant::now();
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => return stream,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    assert!(start.elapsed() < Duration::from_secs(5), "Timed out waiting for a connection");
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("Unable to accept: {}", err),
+            }
+        }
+    }
+
+    // read the head of an upgrade request or response sent by the event loop
+    fn read_head<T, P>(stream: &mut StdStream, parse: P) -> T
+    where
+        P: Fn(&[u8]) -> Result<Option<(T, usize)>>,
+    {
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            if let Some((head, _)) = parse(&buf).unwrap() {
+                return head;
+            }
+            let len = stream.read(&mut chunk).unwrap();
+            assert!(len > 0, "The event loop closed the connection");
+            buf.extend(&chunk[..len]);
+        }
+    }
+
+    fn wait_until<F: Fn() -> bool>(what: &str, done: F) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out waiting until {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn url_schemes() {
+        assert_eq!(url_host_port("tcp://127.0.0.1:3012").unwrap(), ("127.0.0.1".to_string(), 3012));
+        assert_eq!(url_host_port("ws://example.com/chat").unwrap(), ("example.com".to_string(), 80));
+        assert_eq!(url_host_port("wss://example.com").unwrap(), ("example.com".to_string(), 443));
+        assert_eq!(url_host_port("ws://[::1]:9000").unwrap(), ("::1".to_string(), 9000));
+        assert_eq!(url_host_port("127.0.0.1:3012").unwrap(), ("127.0.0.1".to_string(), 3012));
+        assert_eq!(url_host_port("localhost:3012").unwrap(), ("localhost".to_string(), 3012));
+        assert_eq!(url_host_port("[::1]:3012").unwrap(), ("::1".to_string(), 3012));
+    }
+
+    #[test]
+    fn invalid_urls() {
+        assert!(url_host_port("tcp://127.0.0.1").is_err());
+        assert!(url_host_port("unix:///tmp/socket").is_err());
+        assert!(url_host_port("http://example.com").is_err());
+        assert!(url_host_port("127.0.0.1").is_err());
+        assert!(url_host_port("127.0.0.1:port").is_err());
+        assert!(url_host_port(":3012").is_err());
+        assert!(url_to_addrs("tcp://127.0.0.1").is_err());
+        assert!(url_to_addrs("ws://example.com:port").is_err());
+        assert_eq!(url_to_addrs("tcp://127.0.0.1:3012").unwrap(), vec!["127.0.0.1:3012".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn connect_timeout() {
+        // accepts the connection but never answers the WebSocket upgrade
+        let silent = StdListener::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap();
+        let failed = Arc::new(Mutex::new(0));
+
+        let mut settings = Settings::default();
+        settings.connect_timeout = 200;
+        let factory_failed = failed.clone();
+        let mut socket = Builder::new()
+            .with_settings(settings)
+            .with_codec(WebSocket::default())
+            .build(move |_| Dial { failed: factory_failed.clone() })
+            .unwrap();
+        socket.connect(format!("ws://{}/", addr)).unwrap();
+        let start = Instant::now();
+        let handle = socket.spawn().unwrap();
+
+        // the loop gives up on the upgrade and closes the socket
+        let mut peer = silent.accept().unwrap().0;
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut request = Vec::new();
+        peer.read_to_end(&mut request).unwrap();
+        assert!(request.starts_with(b"GET / HTTP/1.1"));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // without connections or listeners left the loop stops
+        handle.join().unwrap();
+        assert_eq!(*failed.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn listen_on_two_addresses() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let factory_seen = seen.clone();
+        let socket = Builder::new()
+            .build(move |sender| Record { sender, seen: factory_seen.clone() })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addrs = socket.local_addrs();
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0] != addrs[1]);
+        let handle = socket.spawn().unwrap();
+
+        let mut clients = Vec::new();
+        for (idx, addr) in addrs.iter().enumerate() {
+            let client = StdStream::connect(addr).unwrap();
+            wait_until("the connection opened", || seen.lock().unwrap().len() > idx);
+            assert_eq!(seen.lock().unwrap()[idx], Seen::Open(client.local_addr().unwrap(), *addr));
+            clients.push(client);
+        }
+
+        handle.shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn stop_and_restart_listening() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let factory_seen = seen.clone();
+        let handle = Builder::new()
+            .build(move |sender| Record { sender, seen: factory_seen.clone() })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let mut client = StdStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        wait_until("the connection opened", || opened(&seen) == 1);
+
+        // connects racing the command are still accepted, the ones after it are refused
+        handle.sender().stop_listening(addr).unwrap();
+        wait_until("connects are refused", || StdStream::connect(addr).is_err());
+        let before = opened(&seen);
+
+        // the connection accepted earlier is still served
+        client.write_all(b"ping").unwrap();
+        let mut echo = [0; 4];
+        client.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"ping");
+
+        handle.sender().listen(addr).unwrap();
+        wait_until("connects are accepted", || StdStream::connect(addr).is_ok());
+        wait_until("the new connection opened", || opened(&seen) > before);
+
+        handle.shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn run_mode_auto() {
+        // without listeners the loop stops with its last connection
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (handle, listener) = client(RunMode::Auto, &seen);
+        drop(listener.accept().unwrap());
+        join_within(handle);
+        assert_eq!(seen.lock().unwrap().last(), Some(&Seen::Close));
+
+        // a listening loop waits for the next connection
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let factory_seen = seen.clone();
+        let handle = Builder::new()
+            .build(move |sender| Record { sender, seen: factory_seen.clone() })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = handle.local_addr().unwrap();
+        drop(StdStream::connect(addr).unwrap());
+        wait_until("the connection closed", || seen.lock().unwrap().contains(&Seen::Close));
+        let client = StdStream::connect(addr).unwrap();
+        wait_until("the next connection opened", || opened(&seen) == 2);
+
+        handle.shutdown().unwrap();
+        join_within(handle);
+    }
+
+    #[test]
+    fn run_mode_until_idle() {
+        // the loop stops with its last connection although it is still listening
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let factory_seen = seen.clone();
+        let settings = Settings { run_mode: RunMode::UntilIdle, ..Settings::default() };
+        let handle = Builder::new()
+            .with_settings(settings)
+            .build(move |sender| Record { sender, seen: factory_seen.clone() })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let client = StdStream::connect(handle.local_addr().unwrap()).unwrap();
+        wait_until("the connection opened", || opened(&seen) == 1);
+        drop(client);
+        join_within(handle);
+        assert_eq!(seen.lock().unwrap().last(), Some(&Seen::Close));
+    }
+
+    #[test]
+    fn run_mode_until_shutdown() {
+        // the loop outlives its last connection and still takes commands
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (handle, listener) = client(RunMode::UntilShutdown, &seen);
+        drop(listener.accept().unwrap());
+        wait_until("the connection closed", || seen.lock().unwrap().contains(&Seen::Close));
+
+        handle.sender().connect(format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        let peer = listener.accept().unwrap();
+        wait_until("the next connection opened", || opened(&seen) == 2);
+
+        handle.shutdown().unwrap();
+        join_within(handle);
+    }
+
+    #[test]
+    fn redial_lost_connection() {
+        let listener = StdListener::bind("127.0.0.1:0").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let settings = Settings { reconnect: Reconnect::Fixed { delay: 100, max_attempts: 2 }, ..Settings::default() };
+        let mut socket = Builder::new()
+            .with_settings(settings)
+            .build(Redial { seen: seen.clone(), attempts: attempts.clone() })
+            .unwrap();
+        socket.connect(format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        let handle = socket.spawn().unwrap();
+
+        // the lost connection is dialed again
+        drop(accept_within(&listener));
+        let peer = accept_within(&listener);
+        wait_until("the connection opened again", || opened(&seen) == 2);
+        assert_eq!(*attempts.lock().unwrap(), vec![1]);
+
+        // once nobody answers anymore the policy gives up after its attempts
+        drop(listener);
+        drop(peer);
+        join_within(handle);
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 1, 2]);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.iter().filter(|seen| **seen == Seen::ConnectFailed).count(), 2);
+    }
+
+    #[test]
+    fn graceful_shutdown_waits() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handle = framed_server(&seen);
+        let client = StdStream::connect(handle.local_addr().unwrap()).unwrap();
+        wait_until("the connection opened", || opened(&seen) == 1);
+
+        let start = Instant::now();
+        handle.shutdown_gracefully(5000).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(!seen.lock().unwrap().contains(&Seen::Close));
+
+        // the loop stops as soon as its last connection is gone
+        drop(client);
+        let socket = join_within(handle);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(socket.cut_off().is_empty());
+    }
+
+    #[test]
+    fn graceful_shutdown_deadline() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handle = framed_server(&seen);
+        let client = StdStream::connect(handle.local_addr().unwrap()).unwrap();
+        wait_until("the connection opened", || opened(&seen) == 1);
+
+        // the client never answers the close
+        let start = Instant::now();
+        handle.shutdown_gracefully(300).unwrap();
+        let socket = join_within(handle);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        let cut_off = socket.cut_off();
+        assert_eq!(cut_off.len(), 1);
+        assert_eq!(cut_off[0].peer_addr, Some(client.local_addr().unwrap()));
+        assert!(matches!(cut_off[0].endpoint, Endpoint::Server));
+    }
+
+    #[test]
+    fn open_once_after_handshake() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let factory_seen = seen.clone();
+        let handle = Builder::new()
+            .with_codec(WebSocket::default())
+            .build(move |sender| Record { sender, seen: factory_seen.clone() })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        // an accepted connection opens once the upgrade is answered
+        let mut client = StdStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(opened(&seen), 0);
+        let request = Request::from_url(&Url::parse(&format!("ws://{}/", addr)).unwrap()).unwrap();
+        request.format(&mut client).unwrap();
+        let response = read_head(&mut client, Response::parse);
+        response.validate(request.key().unwrap()).unwrap();
+        wait_until("the accepted connection opened", || opened(&seen) == 1);
+        assert_eq!(seen.lock().unwrap()[0], Seen::Open(client.local_addr().unwrap(), addr));
+
+        // a dialed connection opens once the other endpoint accepted the upgrade
+        let listener = StdListener::bind("127.0.0.1:0").unwrap();
+        handle.sender().connect(format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+        let mut peer = accept_within(&listener);
+        peer.set_nonblocking(false).unwrap();
+        let request = read_head(&mut peer, Request::parse);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(opened(&seen), 1);
+        Response::from_request(&request).unwrap().format(&mut peer).unwrap();
+        wait_until("the dialed connection opened", || opened(&seen) == 2);
+        assert_eq!(seen.lock().unwrap()[1], Seen::Open(listener.local_addr().unwrap(), peer.peer_addr().unwrap()));
+
+        // and neither opens again
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(opened(&seen), 2);
+        handle.shutdown().unwrap();
+        join_within(handle);
+    }
+}
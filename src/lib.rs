@@ -7,30 +7,68 @@ unused_import_braces)]
 
 extern crate httparse;
 extern crate mio;
+extern crate net2;
 extern crate sha1;
 extern crate rand;
 extern crate url;
 extern crate slab;
 extern crate bytes;
 extern crate byteorder;
+#[cfg(feature = "ssl")]
+extern crate openssl;
+#[cfg(any(feature = "permessage-deflate", feature = "signals"))]
+extern crate libc;
+#[cfg(feature = "permessage-deflate")]
+extern crate libz_sys;
+#[cfg(feature = "async")]
+extern crate futures_channel;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate futures_sink;
 #[macro_use]
 extern crate log;
 
 mod result;
 mod connection;
 mod handler;
+mod handshake;
 mod factory;
 mod message;
+mod frame;
 mod protocol;
 mod communication;
 mod io;
 mod stream;
+mod reconnect;
+mod sync_client;
+mod pool;
+#[cfg(feature = "permessage-deflate")]
+mod deflate;
+#[cfg(all(unix, feature = "signals"))]
+mod signals;
+#[cfg(feature = "async")]
+mod async_client;
 pub mod util;
+pub mod codec;
+pub mod events;
+use codec::NewCodec;
 use communication::Command;
+#[cfg(feature = "async")]
+pub use async_client::{AsyncConnection, Connecting};
+pub use codec::Codec;
 pub use communication::Sender;
+pub use connection::Endpoint;
+pub use events::{Event, EventFactory, Outbox};
 pub use factory::Factory;
+pub use frame::Frame;
 pub use handler::Handler;
+pub use handshake::{Handshake, Request, Response};
+pub use io::CutOff;
 pub use message::Message;
+pub use pool::{PoolHandle, SocketPool};
+pub use reconnect::Reconnect;
+pub use sync_client::SyncClient;
 
 use mio::Poll;
 pub use protocol::{CloseCode, OpCode};
@@ -40,6 +78,8 @@ use std::borrow::Borrow;
 use std::default::Default;
 use std::fmt;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
 
 
 pub fn listen<A, F, H>(addr: A, factory: F) -> Result<()>
@@ -69,6 +109,17 @@ where
     Ok(())
 }
 
+/// When an event loop stops by itself, a loop can always be stopped with `Sender::shutdown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    /// Stop once the last connection is gone unless the loop is listening.
+    Auto,
+    /// Stop once the last connection is gone and no reconnect is pending, even while listening.
+    UntilIdle,
+    /// Keep running without connections, for example to wait for `Sender::connect`.
+    UntilShutdown,
+}
+
 /// Socket settings
 #[derive(Debug, Clone, Copy)]
 pub struct Settings {
@@ -84,15 +135,73 @@ pub struct Settings {
     /// Default: false
     pub panic_on_shutdown: bool,
 
+    /// The number of fragments of an incoming message to make room for.
     /// Default: 10
     pub fragments_capacity: usize,
 
+    /// Whether to accept messages split into more than `fragments_capacity` fragments, if false
+    /// such messages fail with a capacity error.
     /// Default: true
     pub fragments_grow: bool,
 
+    /// Outgoing messages larger than this are split into fragments, if the codec supports it.
     /// Default: 65,535
     pub fragment_size: usize,
 
+    /// The largest frame a framing codec will accept from the other endpoint, also the limit of
+    /// a message reassembled from fragments.
+    /// Default: 16,777,216
+    pub max_frame_length: usize,
+
+    /// The longest line a delimiter based codec will buffer from the other endpoint.
+    /// Default: 65,536
+    pub max_line_length: usize,
+
+    /// The type of the messages delivered by the `Raw` codec, which has no framing to carry it.
+    /// Text messages are validated as UTF-8.
+    /// Default: OpCode::Text
+    pub raw_opcode: OpCode,
+
+    /// The time in milliseconds an outbound connection may take to open, including the TLS and
+    /// WebSocket handshakes, before `Handler::on_connect_failed` gets a timeout. 0 waits as long
+    /// as the operating system does.
+    /// Default: 0
+    pub connect_timeout: u64,
+
+    /// The time in milliseconds to wait for the other endpoint to answer a close before
+    /// dropping the connection, 0 waits forever.
+    /// Default: 5,000
+    pub close_timeout: u64,
+
+    /// The time in milliseconds between pings sent to check that the other endpoint is still
+    /// there, 0 disables the keepalive. Requires a codec with control frames.
+    /// Default: 0
+    pub keepalive_interval: u64,
+
+    /// The number of keepalive intervals without hearing from the other endpoint after which
+    /// the connection is closed with `CloseCode::Away`.
+    /// Default: 3
+    pub keepalive_misses: u32,
+
+    /// Whether to compress messages with the other endpoint, requires the `permessage-deflate`
+    /// feature and a codec that can flag compressed frames. Compression is only used if both
+    /// endpoints announce support for it.
+    /// Default: true
+    pub compression: bool,
+
+    /// The smallest outgoing payload that gets compressed.
+    /// Default: 1,024
+    pub compression_threshold: usize,
+
+    /// How client connections are dialed again after they were lost, other than by closing them
+    /// through their `Sender`. Each attempt is announced to `Factory::on_reconnect`.
+    /// Default: Reconnect::Never
+    pub reconnect: Reconnect,
+
+    /// When the event loop stops by itself.
+    /// Default: RunMode::Auto
+    pub run_mode: RunMode,
+
     /// Default: 2048
     pub in_buffer_capacity: usize,
 
@@ -129,8 +238,29 @@ pub struct Settings {
     /// Default: true
     pub shutdown_on_interrupt: bool,
 
+    /// Whether the event loop handles SIGINT, SIGTERM and SIGHUP, requires the `signals`
+    /// feature on unix. SIGINT and SIGTERM shut the loop down, SIGHUP is passed to
+    /// `Factory::on_hangup`. A second SIGINT or SIGTERM shuts down right away.
+    /// Default: false
+    pub handle_signals: bool,
+
+    /// The deadline in milliseconds of the graceful shutdown triggered by SIGINT or SIGTERM,
+    /// 0 shuts down right away.
+    /// Default: 5,000
+    pub signal_shutdown_deadline: u64,
+
     /// Default: false
     pub tcp_nodelay: bool,
+
+    /// Whether listeners set SO_REUSEPORT on unix, so that several event loops or processes can
+    /// listen on the same address and the kernel spreads the connections among them.
+    /// Default: false
+    pub reuse_port: bool,
+
+    /// The number of event loops `Builder::build_pool` runs, each on a thread of its own. More
+    /// than one is only supported on unix, see `Builder::with_event_loops`.
+    /// Default: 1
+    pub event_loops: usize,
 }
 
 impl Default for Settings {
@@ -143,6 +273,17 @@ impl Default for Settings {
             fragments_capacity: 10,
             fragments_grow: true,
             fragment_size: u16::max_value() as usize,
+            max_frame_length: 16 * 1024 * 1024,
+            max_line_length: 64 * 1024,
+            raw_opcode: OpCode::Text,
+            connect_timeout: 0,
+            close_timeout: 5000,
+            keepalive_interval: 0,
+            keepalive_misses: 3,
+            compression: true,
+            compression_threshold: 1024,
+            reconnect: Reconnect::Never,
+            run_mode: RunMode::Auto,
             in_buffer_capacity: 2048,
             in_buffer_grow: true,
             out_buffer_capacity: 2048,
@@ -155,7 +296,11 @@ impl Default for Settings {
             panic_on_io: false,
             panic_on_timeout: false,
             shutdown_on_interrupt: true,
+            handle_signals: false,
+            signal_shutdown_deadline: 5000,
             tcp_nodelay: false,
+            reuse_port: false,
+            event_loops: 1,
         }
     }
 }
@@ -178,6 +323,8 @@ where
         Builder::new().build(factory)
     }
 
+    /// Listen on the first usable address of `addr_spec`. Call it again to listen on further
+    /// addresses from the same event loop, `Factory::listener_connected` tells them apart.
     pub fn bind<A>(mut self, addr_spec: A) -> Result<XnetSocket<F>>
     where
         A: ToSocketAddrs,
@@ -189,7 +336,7 @@ where
                 error!("Unable to listen on {}", addr);
                 last_error = e;
             } else {
-                let actual_addr = self.handler.local_addr().unwrap_or(addr);
+                let actual_addr = self.handler.local_addrs().pop().unwrap_or(addr);
                 info!("Listening for new connections on {}.", actual_addr);
                 return Ok(self);
             }
@@ -213,6 +360,8 @@ where
         Ok(self)
     }
 
+    /// Run the event loop until it is shut down. After a graceful shutdown the returned socket
+    /// reports the connections dropped at the deadline in `cut_off`.
     pub fn run(mut self) -> Result<XnetSocket<F>> {
         self.handler.run(&mut self.poll)?;
         Ok(self)
@@ -229,21 +378,120 @@ where
         self.handler.local_addr()
     }
 
+    /// The addresses of all listeners in the order they were bound.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.handler.local_addrs()
+    }
+
+    /// The connections that were dropped because `Sender::shutdown_gracefully` ran out of time
+    /// during the last `run`.
+    pub fn cut_off(&self) -> &[CutOff] {
+        self.handler.cut_off()
+    }
+
     pub fn sender_handler(&self) -> mio::channel::SyncSender<Command> {
         self.handler.sender_handler()
     }
 }
 
+impl<F> XnetSocket<F>
+where
+    F: Factory + Send + 'static,
+    F::Handler: Send,
+{
+    /// Run the event loop on a thread of its own. Bind the listeners before spawning, the
+    /// returned handle reports their addresses and stops the loop again.
+    pub fn spawn(self) -> Result<SocketHandle<F>> {
+        let sender = self.broadcaster();
+        let local_addrs = self.local_addrs();
+        let thread = thread::Builder::new()
+            .name("xnet-socket".into())
+            .spawn(move || self.run())?;
+        Ok(SocketHandle {
+            sender,
+            local_addrs,
+            thread,
+        })
+    }
+}
+
+/// A handle to an event loop started by `XnetSocket::spawn`.
+pub struct SocketHandle<F>
+where
+    F: Factory,
+{
+    sender: Sender,
+    local_addrs: Vec<SocketAddr>,
+    thread: thread::JoinHandle<Result<XnetSocket<F>>>,
+}
+
+impl<F> SocketHandle<F>
+where
+    F: Factory,
+{
+    /// The broadcaster of the event loop.
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
 
-#[derive(Debug, Clone, Copy)]
+    /// The address of the first listener.
+    pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
+        self.local_addrs.first().cloned().ok_or_else(|| {
+            ::std::io::Error::new(::std::io::ErrorKind::NotFound, "The event loop is not listening.")
+        })
+    }
+
+    /// The addresses the event loop was listening on when it was spawned.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Ask the event loop to stop right away, use `join` to wait for it.
+    pub fn shutdown(&self) -> Result<()> {
+        self.sender.shutdown()
+    }
+
+    /// Ask the event loop to close its connections within `deadline` milliseconds. The socket
+    /// returned by `join` lists the connections that did not make it in `XnetSocket::cut_off`.
+    pub fn shutdown_gracefully(&self, deadline: u64) -> Result<()> {
+        self.sender.shutdown_gracefully(deadline)
+    }
+
+    /// Wait for the event loop to finish and take back the socket, or the error it stopped with.
+    /// After a graceful shutdown, `XnetSocket::cut_off` of the socket reports the connections
+    /// dropped at the deadline.
+    pub fn join(self) -> Result<XnetSocket<F>> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::Internal, "The event loop panicked.")))
+    }
+}
+
+impl<F> fmt::Debug for SocketHandle<F>
+where
+    F: Factory,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SocketHandle")
+            .field("local_addrs", &self.local_addrs)
+            .finish()
+    }
+}
+
+
+#[derive(Clone)]
 pub struct Builder {
     settings: Settings,
+    codec: NewCodec,
 }
 
 // TODO: add convenience methods for each setting
 impl Builder {
     pub fn new() -> Builder {
-        Builder { settings: Settings::default() }
+        Builder {
+            settings: Settings::default(),
+            codec: codec::new_codec(codec::Raw::default()),
+        }
     }
 
 
@@ -253,13 +501,60 @@ impl Builder {
     {
         Ok(XnetSocket {
                poll: Poll::new()?,
-               handler: io::Handler::new(factory, self.settings),
+               handler: io::Handler::new(factory, self.settings, self.codec.clone()),
            })
     }
 
 
+    /// Build a socket that delivers the events of its connections to the returned `Receiver`
+    /// instead of `Handler` callbacks, the returned `Outbox` replies to them.
+    pub fn build_events(&self) -> Result<(XnetSocket<EventFactory>, Outbox, mpsc::Receiver<Event>)> {
+        let (tx, rx) = mpsc::channel();
+        let factory = EventFactory::new(tx);
+        let socket = self.build(factory)?;
+        let outbox = socket.handler.factory().outbox(socket.broadcaster());
+        Ok((socket, outbox, rx))
+    }
+
+
+    /// Build a pool of `Settings::event_loops` event loops, each with its own clone of
+    /// `factory`.
+    pub fn build_pool<F>(&self, factory: F) -> Result<SocketPool<F>>
+    where
+        F: Factory + Clone,
+    {
+        SocketPool::new(self, factory)
+    }
+
+
     pub fn with_settings(&mut self, settings: Settings) -> &mut Builder {
         self.settings = settings;
         self
     }
+
+    /// Set the number of event loops of `build_pool`.
+    ///
+    /// The loops of a pool share their listeners through SO_REUSEPORT, which only exists on unix.
+    /// Elsewhere `build_pool` fails with a `Kind::Internal` error for more than one event loop
+    /// rather than handing accepted connections from one loop to another.
+    pub fn with_event_loops(&mut self, event_loops: usize) -> &mut Builder {
+        self.settings.event_loops = event_loops;
+        self
+    }
+
+    /// Set the codec used to frame messages on every connection of the socket.
+    /// Each connection gets its own clone of `codec`. Default: `codec::Raw`
+    pub fn with_codec<C>(&mut self, codec: C) -> &mut Builder
+    where
+        C: Codec + Clone + Send + Sync + 'static,
+    {
+        self.codec = codec::new_codec(codec);
+        self
+    }
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Builder").field("settings", &self.settings).finish()
+    }
 }
@@ -0,0 +1,345 @@
+//! Several event loops serving the same listeners, one thread each.
+//!
+//! Every loop of a pool has its own `Poll`, connections and timer. The loops listen on the same
+//! addresses with SO_REUSEPORT, so the kernel spreads accepted connections among them, and
+//! outbound connections are handed to the loops in turn. A broadcast from any `Sender` of the
+//! pool is passed on to the other loops and reaches every connection. Pools of more than one
+//! event loop are therefore limited to unix.
+
+use super::{Builder, CutOff, SocketHandle, XnetSocket};
+use communication::Sender;
+use factory::Factory;
+use io::Peer;
+use result::{Result, Error, Kind};
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// The event loops built by `Builder::build_pool`.
+pub struct SocketPool<F>
+where
+    F: Factory,
+{
+    sockets: Vec<XnetSocket<F>>,
+    next_connect: usize,
+}
+
+impl<F> SocketPool<F>
+where
+    F: Factory + Clone,
+{
+    pub fn new(builder: &Builder, factory: F) -> Result<SocketPool<F>> {
+        let mut settings = builder.settings;
+        let loops = settings.event_loops.max(1);
+        if loops > 1 && cfg!(not(unix)) {
+            // without SO_REUSEPORT only the first loop could bind the shared listeners
+            return Err(Error::new(Kind::Internal,
+                                  format!("A pool of {} event loops needs SO_REUSEPORT, which is only available on unix.", loops)));
+        }
+        settings.reuse_port = settings.reuse_port || loops > 1;
+        let mut builder = builder.clone();
+        builder.with_settings(settings);
+
+        let mut sockets = Vec::with_capacity(loops);
+        for _ in 0..loops {
+            sockets.push(builder.build(factory.clone())?);
+        }
+        let peers: Vec<Peer> = sockets.iter().map(|socket| socket.handler.peer()).collect();
+        for (i, socket) in sockets.iter_mut().enumerate() {
+            socket.handler.set_connection_ids(i as u32, loops as u32);
+            for (j, peer) in peers.iter().enumerate() {
+                if i != j {
+                    socket.handler.add_peer(peer.clone());
+                }
+            }
+        }
+        Ok(SocketPool { sockets, next_connect: 0 })
+    }
+}
+
+impl<F> SocketPool<F>
+where
+    F: Factory,
+{
+    /// Listen on the first usable address of `addr_spec` from every event loop. A port of 0 is
+    /// chosen by the first loop and shared by the others.
+    pub fn bind<A>(mut self, addr_spec: A) -> Result<SocketPool<F>>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut sockets = self.sockets.drain(..);
+        let first = sockets.next().expect("A pool has at least one event loop.").bind(addr_spec)?;
+        let addr = first.local_addrs().pop().expect("The event loop just bound an address.");
+
+        let mut bound = vec![first];
+        for socket in sockets {
+            bound.push(socket.bind(addr)?);
+        }
+        self.sockets = bound;
+        Ok(self)
+    }
+
+    /// Queue a connection to `url` on the next event loop in turn.
+    pub fn connect(&mut self, url: String) -> Result<&mut SocketPool<F>> {
+        let index = self.next_connect % self.sockets.len();
+        self.next_connect = self.next_connect.wrapping_add(1);
+        self.sockets[index].connect(url)?;
+        Ok(self)
+    }
+
+    /// A sender on the first event loop. Only its broadcasts reach the connections of every
+    /// loop, other commands such as `shutdown`, `connect`, `listen` or `timeout` concern the
+    /// first loop alone. Use `SocketPool::connect` and `PoolHandle::shutdown` for the whole pool.
+    pub fn broadcaster(&self) -> Sender {
+        self.sockets[0].broadcaster()
+    }
+
+    /// The addresses the pool listens on in the order they were bound.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets[0].local_addrs()
+    }
+
+    /// The number of event loops.
+    pub fn event_loops(&self) -> usize {
+        self.sockets.len()
+    }
+
+    /// The connections of every event loop that were dropped because a graceful shutdown ran out
+    /// of time during the last run.
+    pub fn cut_off(&self) -> Vec<CutOff> {
+        self.sockets.iter().flat_map(|socket| socket.cut_off().iter().cloned()).collect()
+    }
+}
+
+impl<F> SocketPool<F>
+where
+    F: Factory + Send + 'static,
+    F::Handler: Send,
+{
+    /// Start every event loop on a thread of its own.
+    pub fn spawn(self) -> Result<PoolHandle<F>> {
+        let local_addrs = self.local_addrs();
+        let mut handles = Vec::with_capacity(self.sockets.len());
+        for socket in self.sockets {
+            match socket.spawn() {
+                Ok(handle) => handles.push(handle),
+                Err(err) => {
+                    for handle in handles {
+                        let _ = handle.shutdown();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(PoolHandle { handles, local_addrs })
+    }
+
+    /// Run every event loop until all of them stopped.
+    pub fn run(self) -> Result<SocketPool<F>> {
+        self.spawn()?.join()
+    }
+}
+
+impl<F> fmt::Debug for SocketPool<F>
+where
+    F: Factory,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SocketPool")
+            .field("event_loops", &self.sockets.len())
+            .finish()
+    }
+}
+
+/// A handle to the running event loops of a pool.
+pub struct PoolHandle<F>
+where
+    F: Factory,
+{
+    handles: Vec<SocketHandle<F>>,
+    local_addrs: Vec<SocketAddr>,
+}
+
+impl<F> PoolHandle<F>
+where
+    F: Factory,
+{
+    /// The sender of the first event loop, see `SocketPool::broadcaster`. Only its broadcasts
+    /// reach every loop.
+    pub fn sender(&self) -> &Sender {
+        self.handles[0].sender()
+    }
+
+    /// The address of the first listener.
+    pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
+        self.handles[0].local_addr()
+    }
+
+    /// The addresses the pool was listening on when it was spawned.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Ask every event loop to stop right away.
+    pub fn shutdown(&self) -> Result<()> {
+        self.handles.iter().map(|handle| handle.shutdown()).fold(Ok(()), Result::and)
+    }
+
+    /// Ask every event loop to close its connections within `deadline` milliseconds. The pool
+    /// returned by `join` lists the connections that did not make it in `SocketPool::cut_off`.
+    pub fn shutdown_gracefully(&self, deadline: u64) -> Result<()> {
+        self.handles
+            .iter()
+            .map(|handle| handle.shutdown_gracefully(deadline))
+            .fold(Ok(()), Result::and)
+    }
+
+    /// Wait for every event loop to finish, returns the first error any of them stopped with.
+    pub fn join(self) -> Result<SocketPool<F>> {
+        let mut sockets = Vec::with_capacity(self.handles.len());
+        let mut error: Option<Error> = None;
+        for handle in self.handles {
+            match handle.join() {
+                Ok(socket) => sockets.push(socket),
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        match error {
+            Some(err) => Err(err),
+            None if sockets.is_empty() => Err(Error::new(Kind::Internal, "The pool has no event loops.")),
+            None => Ok(SocketPool { sockets, next_connect: 0 }),
+        }
+    }
+}
+
+impl<F> fmt::Debug for PoolHandle<F>
+where
+    F: Factory,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoolHandle")
+            .field("event_loops", &self.handles.len())
+            .field("local_addrs", &self.local_addrs)
+            .finish()
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use handler::Handler;
+    use handshake::Handshake;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    struct Report {
+        out: Sender,
+        opened: mpsc::Sender<Sender>,
+    }
+
+    impl Handler for Report {
+        fn on_open(&mut self, _: Handshake) -> Result<()> {
+            self.opened.send(self.out.clone()).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn broadcast_reaches_every_loop() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        let mut pool = Builder::new()
+            .with_event_loops(2)
+            .build_pool(move |out| Report { out, opened: tx.clone() })
+            .unwrap();
+        assert_eq!(pool.event_loops(), 2);
+        pool.connect(addr.to_string()).unwrap();
+        pool.connect(addr.to_string()).unwrap();
+        let handle = pool.spawn().unwrap();
+
+        let mut peers = vec![server.accept().unwrap().0, server.accept().unwrap().0];
+        let first = rx.recv().unwrap();
+        let second = rx.recv().unwrap();
+        assert!(first.connection_id() != second.connection_id());
+
+        // a broadcast from a connection of either loop reaches both
+        first.broadcast(&b"one"[..]).unwrap();
+        second.broadcast(&b"two"[..]).unwrap();
+        for peer in &mut peers {
+            let mut buf = [0u8; 6];
+            peer.read_exact(&mut buf).unwrap();
+            buf.sort();
+            let mut expected = *b"onetwo";
+            expected.sort();
+            assert_eq!(buf, expected);
+        }
+
+        handle.shutdown().unwrap();
+        assert_eq!(handle.join().unwrap().event_loops(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn graceful_shutdown_reports_cut_off() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        let mut pool = Builder::new()
+            .with_event_loops(2)
+            .build_pool(move |out| Report { out, opened: tx.clone() })
+            .unwrap();
+        pool.connect(addr.to_string()).unwrap();
+        let handle = pool.spawn().unwrap();
+
+        // the peer never reads, so the message cannot be flushed before the deadline
+        let peer = server.accept().unwrap().0;
+        let out = rx.recv().unwrap();
+        out.send(vec![0u8; 16 << 20]).unwrap();
+        handle.shutdown_gracefully(100).unwrap();
+
+        let cut_off = handle.join().unwrap().cut_off();
+        assert_eq!(cut_off.len(), 1);
+        assert_eq!(cut_off[0].connection_id, out.connection_id());
+        assert!(cut_off[0].unsent > 0);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn broadcast_past_stopped_loop() {
+        let (tx, rx) = mpsc::channel();
+        let mut settings = ::Settings::default();
+        settings.max_connections = 1;
+        settings.queue_size = 4;
+        let pool = Builder::new()
+            .with_settings(settings)
+            .with_event_loops(2)
+            .build_pool(move |out| Report { out, opened: tx.clone() })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let mut handle = pool.spawn().unwrap();
+
+        // the stopped loop keeps its queue alive, but nobody drains it anymore
+        let second = handle.handles.remove(1);
+        second.shutdown().unwrap();
+        let stopped = second.join().unwrap();
+
+        let first = handle.handles.remove(0);
+        for _ in 0..64 {
+            first.sender().broadcast(&b"more than the queue holds"[..]).unwrap();
+        }
+        first.shutdown().unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || done_tx.send(first.join().is_ok()).unwrap());
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+}
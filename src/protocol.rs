@@ -12,6 +12,12 @@ pub enum OpCode {
     Binary,
     /// Indicates a close control frame.
     Close,
+    /// Indicates a ping control frame.
+    Ping,
+    /// Indicates a pong control frame.
+    Pong,
+    /// Indicates a control frame announcing the extensions an endpoint supports.
+    Extensions,
     /// Indicates an invalid opcode was received.
     Bad,
 }
@@ -19,10 +25,7 @@ pub enum OpCode {
 impl OpCode {
     /// Test whether the opcode indicates a control frame.
     pub fn is_control(&self) -> bool {
-        match *self {
-            Text | Binary | Continue => false,
-            _ => true,
-        }
+        matches!(*self, Close | Ping | Pong | Extensions)
     }
 }
 
@@ -33,6 +36,9 @@ impl fmt::Display for OpCode {
             Text => write!(f, "TEXT"),
             Binary => write!(f, "BINARY"),
             Close => write!(f, "CLOSE"),
+            Ping => write!(f, "PING"),
+            Pong => write!(f, "PONG"),
+            Extensions => write!(f, "EXTENSIONS"),
             Bad => write!(f, "BAD"),
         }
     }
@@ -45,6 +51,9 @@ impl Into<u8> for OpCode {
             Text => 1,
             Binary => 2,
             Close => 8,
+            Ping => 9,
+            Pong => 10,
+            Extensions => 11,
             Bad => {
                 debug_assert!(false, "Attempted to convert invalid opcode to u8. This is a bug.");
                 8 // if this somehow happens, a close frame will help us tear down quickly
@@ -60,6 +69,9 @@ impl From<u8> for OpCode {
             1 => Text,
             2 => Binary,
             8 => Close,
+            9 => Ping,
+            10 => Pong,
+            11 => Extensions,
             _ => Bad,
         }
     }
@@ -154,6 +166,17 @@ mod test {
         assert_eq!(byte, 1u8);
     }
 
+    #[test]
+    fn control_opcodes() {
+        for &opcode in &[OpCode::Close, OpCode::Ping, OpCode::Pong, OpCode::Extensions] {
+            let byte: u8 = opcode.into();
+            assert_eq!(OpCode::from(byte), opcode);
+            assert!(opcode.is_control());
+        }
+        assert_eq!(OpCode::from(9u8), OpCode::Ping);
+        assert_eq!(OpCode::from(10u8), OpCode::Pong);
+    }
+
     #[test]
     fn closecode_from_u16() {
         let byte = 1008u16;
@@ -0,0 +1,76 @@
+use rand;
+
+/// How client connections are dialed again once they are lost.
+///
+/// Attempts are counted from 1 since the connection to the url was last open, a `max_attempts`
+/// of 0 keeps trying forever. Every attempt builds a new handler through the `Factory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reconnect {
+    /// Lost connections stay lost.
+    #[default]
+    Never,
+    /// Wait the same number of milliseconds before every attempt.
+    Fixed { delay: u64, max_attempts: u32 },
+    /// Double the wait in milliseconds with every attempt up to `max`. A random jitter of up to
+    /// half the wait keeps peers that lost their connections together from dialing in lockstep.
+    Exponential { initial: u64, max: u64, max_attempts: u32 },
+}
+
+impl Reconnect {
+    /// The time in milliseconds to wait before the given attempt, `None` once the policy gives up.
+    pub fn delay(&self, attempt: u32) -> Option<u64> {
+        match *self {
+            Reconnect::Never => None,
+            Reconnect::Fixed { delay, max_attempts } => {
+                if attempt == 0 || (max_attempts > 0 && attempt > max_attempts) {
+                    None
+                } else {
+                    Some(delay)
+                }
+            }
+            Reconnect::Exponential { initial, max, max_attempts } => {
+                if attempt == 0 || (max_attempts > 0 && attempt > max_attempts) {
+                    None
+                } else {
+                    let delay = initial.saturating_mul(1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX)).min(max);
+                    let jitter = delay / 2;
+                    if jitter > 0 {
+                        Some(delay - jitter + rand::random::<u64>() % (jitter + 1))
+                    } else {
+                        Some(delay)
+                    }
+                }
+            }
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+
+    #[test]
+    fn fixed_delay() {
+        let policy = Reconnect::Fixed { delay: 500, max_attempts: 3 };
+        assert_eq!(policy.delay(1), Some(500));
+        assert_eq!(policy.delay(3), Some(500));
+        assert_eq!(policy.delay(4), None);
+        assert_eq!(Reconnect::Fixed { delay: 500, max_attempts: 0 }.delay(1000), Some(500));
+        assert_eq!(Reconnect::Never.delay(1), None);
+    }
+
+    #[test]
+    fn exponential_delay() {
+        let policy = Reconnect::Exponential { initial: 100, max: 1000, max_attempts: 0 };
+        for _ in 0..100 {
+            let first = policy.delay(1).unwrap();
+            assert!(first >= 50 && first <= 100);
+            let third = policy.delay(3).unwrap();
+            assert!(third >= 200 && third <= 400);
+            let capped = policy.delay(40).unwrap();
+            assert!(capped >= 500 && capped <= 1000);
+        }
+        assert_eq!(Reconnect::Exponential { initial: 100, max: 1000, max_attempts: 2 }.delay(3), None);
+    }
+}
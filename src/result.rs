@@ -1,6 +1,12 @@
 use communication::Command;
 use httparse;
 use mio;
+#[cfg(feature = "ssl")]
+use mio::tcp::TcpStream;
+#[cfg(feature = "ssl")]
+use openssl::error::ErrorStack;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{self, HandshakeError};
 use std::borrow::Cow;
 use std::convert::{From, Into};
 use std::error::Error as StdError;
@@ -22,6 +28,9 @@ pub enum Kind {
     Queue(mio::channel::SendError<Command>),
     /// Indicates a failure to schedule a timeout on the EventLoop.
     Timer(mio::timer::TimerError),
+    /// Indicates a failure of the TLS library, for example during the handshake.
+    #[cfg(feature = "ssl")]
+    Ssl(ErrorStack),
     Custom(Box<StdError + Send + Sync>),
 }
 
@@ -81,6 +90,8 @@ impl StdError for Error {
             Kind::Http(_) => "Unable to parse HTTP",
             Kind::Queue(_) => "Unable to send signal on event loop",
             Kind::Timer(_) => "Unable to schedule timeout on event loop",
+            #[cfg(feature = "ssl")]
+            Kind::Ssl(_) => "TLS failure",
             Kind::Custom(ref err) => err.description(),
         }
     }
@@ -89,6 +100,8 @@ impl StdError for Error {
         match self.kind {
             Kind::Encoding(ref err) => Some(err),
             Kind::Io(ref err) => Some(err),
+            #[cfg(feature = "ssl")]
+            Kind::Ssl(ref err) => Some(err),
             Kind::Custom(ref err) => Some(err.as_ref()),
             _ => None,
         }
@@ -146,6 +159,42 @@ impl From<mio::timer::TimerError> for Error {
     }
 }
 
+#[cfg(feature = "ssl")]
+impl From<ErrorStack> for Error {
+    fn from(err: ErrorStack) -> Error {
+        let detail = err.to_string();
+        Error::new(Kind::Ssl(err), detail)
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl<'a> From<&'a ssl::Error> for Error {
+    fn from(err: &'a ssl::Error) -> Error {
+        if let Some(stack) = err.ssl_error() {
+            Error::from(stack.clone())
+        } else if let Some(io_err) = err.io_error() {
+            // keep the os error around, a refused connect is detected by its code
+            match io_err.raw_os_error() {
+                Some(code) => Error::from(io::Error::from_raw_os_error(code)),
+                None => Error::from(io::Error::new(io_err.kind(), io_err.to_string())),
+            }
+        } else {
+            Error::from(io::Error::new(io::ErrorKind::ConnectionAborted, err.to_string()))
+        }
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl From<HandshakeError<TcpStream>> for Error {
+    fn from(err: HandshakeError<TcpStream>) -> Error {
+        match err {
+            HandshakeError::SetupFailure(err) => Error::from(err),
+            HandshakeError::Failure(mid) => Error::from(mid.error()),
+            HandshakeError::WouldBlock(_) => Error::new(Kind::Internal, "TLS handshake was interrupted."),
+        }
+    }
+}
+
 impl From<Utf8Error> for Error {
     fn from(err: Utf8Error) -> Error {
         let detail = err.to_string();
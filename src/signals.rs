@@ -0,0 +1,192 @@
+//! Delivery of SIGINT, SIGTERM and SIGHUP to event loops through a self-pipe.
+//!
+//! The signal handler writes the signal number into the pipe of every event loop that handles
+//! signals, which then see it as a readable event. Without such a loop the signal gets its
+//! default disposition again.
+
+use libc;
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM};
+
+const SLOTS: usize = 16;
+// the write ends of the pipes of all event loops handling signals
+static PIPES: [AtomicI32; SLOTS] = [const { AtomicI32::new(-1) }; SLOTS];
+static INSTALL: Once = Once::new();
+// pipes of dropped event loops, kept open since a racing handler may still write to them
+static SPARE: Mutex<Vec<(RawFd, RawFd)>> = Mutex::new(Vec::new());
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    #[cfg(target_os = "linux")]
+    let errno = unsafe { *libc::__errno_location() };
+
+    let byte = sig as u8;
+    let ptr: *const u8 = &byte;
+    let mut delivered = false;
+    for slot in PIPES.iter() {
+        let fd = slot.load(Ordering::SeqCst);
+        if fd >= 0 {
+            unsafe {
+                libc::write(fd, ptr as *const libc::c_void, 1);
+            }
+            delivered = true;
+        }
+    }
+    if !delivered {
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe {
+        *libc::__errno_location() = errno;
+    }
+}
+
+fn install() {
+    for &sig in &[SIGINT, SIGTERM, SIGHUP] {
+        unsafe {
+            let mut action: libc::sigaction = ::std::mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(sig, &action, ::std::ptr::null_mut()) != 0 {
+                error!("Unable to install handler for signal {}: {}", sig, io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+fn set_flags(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The receiving end of the signals of one event loop.
+pub struct SignalPipe {
+    read: RawFd,
+    write: RawFd,
+    slot: usize,
+}
+
+impl SignalPipe {
+    pub fn new() -> io::Result<SignalPipe> {
+        let spare = SPARE.lock().unwrap_or_else(|err| err.into_inner()).pop();
+        let (read, write) = match spare {
+            Some(fds) => fds,
+            None => SignalPipe::open()?,
+        };
+
+        let slot = PIPES.iter().position(|slot| slot.compare_exchange(-1, write, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+        let pipe = SignalPipe { read, write, slot: slot.unwrap_or(SLOTS) };
+        match slot {
+            Some(_) => {
+                // drop whatever reached a reused pipe after its event loop went away
+                pipe.received();
+                INSTALL.call_once(install);
+                Ok(pipe)
+            }
+            None => Err(io::Error::other("Too many event loops are handling signals.")),
+        }
+    }
+
+    fn open() -> io::Result<(RawFd, RawFd)> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Err(err) = set_flags(fds[0]).and_then(|_| set_flags(fds[1])) {
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            return Err(err);
+        }
+        Ok((fds[0], fds[1]))
+    }
+
+    pub fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.register(&EventedFd(&self.read), token, Ready::readable(), PollOpt::level())
+    }
+
+    pub fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&EventedFd(&self.read))
+    }
+
+    /// Take the signals received since the last call.
+    pub fn received(&self) -> Vec<libc::c_int> {
+        let mut signals = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let len = unsafe { libc::read(self.read, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len <= 0 {
+                break;
+            }
+            signals.extend(buf[..len as usize].iter().map(|&sig| libc::c_int::from(sig)));
+        }
+        signals
+    }
+}
+
+impl Drop for SignalPipe {
+    fn drop(&mut self) {
+        if let Some(slot) = PIPES.get(self.slot) {
+            slot.store(-1, Ordering::SeqCst);
+        }
+        // A handler that loaded the write end before the store above can still write to it, so
+        // the pipe stays open for the next event loop instead of freeing the descriptor for reuse.
+        SPARE.lock().unwrap_or_else(|err| err.into_inner()).push((self.read, self.write));
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+
+    // raised signals reach every pipe in the process
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn deliver_hangup() {
+        let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+        let pipe = SignalPipe::new().unwrap();
+        assert!(pipe.received().is_empty());
+        unsafe {
+            libc::raise(SIGHUP);
+        }
+        assert_eq!(pipe.received(), vec![SIGHUP]);
+    }
+
+    #[test]
+    fn write_after_drop() {
+        let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+        let pipe = SignalPipe::new().unwrap();
+        let write = pipe.write;
+        drop(pipe);
+
+        // what a handler racing the drop would do
+        let byte = SIGHUP as u8;
+        let ptr: *const u8 = &byte;
+        let len = unsafe { libc::write(write, ptr as *const libc::c_void, 1) };
+        assert_eq!(len, 1);
+
+        let pipe = SignalPipe::new().unwrap();
+        assert!(pipe.received().is_empty());
+    }
+}
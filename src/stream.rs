@@ -1,9 +1,15 @@
 use bytes::{Buf, BufMut};
 use mio::tcp::TcpStream;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{ErrorCode, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslConnector, SslStream};
 use result::{Result, Error, Kind};
 use std::io;
 use std::io::ErrorKind::WouldBlock;
-use std::net::SocketAddr;
+#[cfg(feature = "ssl")]
+use std::mem::replace;
+use std::net::{SocketAddr, Shutdown};
+#[cfg(feature = "ssl")]
+use std::result::Result as StdResult;
 
 fn map_non_block<T>(res: io::Result<T>) -> io::Result<Option<T>> {
     match res {
@@ -54,6 +60,16 @@ use self::Stream::*;
 
 pub enum Stream {
     Tcp(TcpStream),
+    #[cfg(feature = "ssl")]
+    Tls(TlsStream),
+}
+
+#[cfg(feature = "ssl")]
+pub enum TlsStream {
+    Live(SslStream<TcpStream>),
+    Negotiating(MidHandshakeSslStream<TcpStream>),
+    // Only seen while a handshake step is in progress
+    Upgrading,
 }
 
 impl Stream {
@@ -61,34 +77,129 @@ impl Stream {
         Tcp(stream)
     }
 
+    /// Start the TLS handshake of an accepted socket.
+    #[cfg(feature = "ssl")]
+    pub fn tls_server(acceptor: &SslAcceptor, stream: TcpStream) -> Result<Stream> {
+        Stream::tls(acceptor.accept(stream))
+    }
+
+    /// Start the TLS handshake of an outbound socket, the connect does not need to be finished.
+    #[cfg(feature = "ssl")]
+    pub fn tls_client(connector: &SslConnector, domain: &str, stream: TcpStream) -> Result<Stream> {
+        Stream::tls(connector.connect(domain, stream))
+    }
+
+    #[cfg(feature = "ssl")]
+    fn tls(res: StdResult<SslStream<TcpStream>, HandshakeError<TcpStream>>) -> Result<Stream> {
+        match res {
+            Ok(stream) => Ok(Tls(TlsStream::Live(stream))),
+            Err(HandshakeError::WouldBlock(mid)) => Ok(Tls(TlsStream::Negotiating(mid))),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    /// Take the plain socket out in order to start a TLS handshake on it.
+    #[cfg(feature = "ssl")]
+    pub fn take_tcp(&mut self) -> Result<TcpStream> {
+        match replace(self, Tls(TlsStream::Upgrading)) {
+            Tcp(sock) => Ok(sock),
+            tls => {
+                *self = tls;
+                Err(Error::new(Kind::Internal, "Attempted to start TLS twice on the same connection."))
+            }
+        }
+    }
+
 
     pub fn evented(&self) -> &TcpStream {
         match *self {
             Tcp(ref sock) => sock,
+            #[cfg(feature = "ssl")]
+            Tls(ref tls) => tls.evented(),
         }
     }
 
     pub fn is_negotiating(&self) -> bool {
         match *self {
             Tcp(_) => false,
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(_)) => false,
+            #[cfg(feature = "ssl")]
+            Tls(_) => true,
+        }
+    }
+
+    /// Whether the TLS handshake waits for the socket to become writable rather than readable.
+    pub fn negotiation_wants_write(&self) -> bool {
+        match *self {
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Negotiating(ref mid)) => mid.error().code() == ErrorCode::WANT_WRITE,
+            _ => false,
         }
     }
 
+    /// Advance the TLS handshake as far as the socket allows.
     pub fn clear_negotiating(&mut self) -> Result<()> {
         match *self {
             Tcp(_) => Err(Error::new(Kind::Internal, "Attempted to clear negotiating flag on non ssl connection.")),
+            #[cfg(feature = "ssl")]
+            Tls(ref mut tls) => {
+                match replace(tls, TlsStream::Upgrading) {
+                    TlsStream::Negotiating(mid) => {
+                        match mid.handshake() {
+                            Ok(stream) => {
+                                *tls = TlsStream::Live(stream);
+                                Ok(())
+                            }
+                            Err(HandshakeError::WouldBlock(mid)) => {
+                                *tls = TlsStream::Negotiating(mid);
+                                Ok(())
+                            }
+                            Err(HandshakeError::Failure(mid)) => {
+                                let err = Error::from(mid.error());
+                                // keep the socket around so that the connection can still be torn down
+                                *tls = TlsStream::Negotiating(mid);
+                                Err(err)
+                            }
+                            Err(HandshakeError::SetupFailure(err)) => Err(Error::from(err)),
+                        }
+                    }
+                    live => {
+                        *tls = live;
+                        Err(Error::new(Kind::Internal, "Attempted to clear negotiating flag on live ssl connection."))
+                    }
+                }
+            }
         }
     }
 
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        match *self {
-            Tcp(ref sock) => sock.peer_addr(),
-        }
+        self.evented().peer_addr()
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.evented().local_addr()
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.evented().take_error()
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
         match *self {
-            Tcp(ref sock) => sock.local_addr(),
+            Tcp(ref sock) => sock.shutdown(how),
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(ref mut stream)) => {
+                // let the other endpoint know that no more data follows before closing our half
+                if let Err(err) = stream.shutdown() {
+                    if err.code() != ErrorCode::WANT_READ && err.code() != ErrorCode::WANT_WRITE {
+                        trace!("Unable to send TLS close notify: {}", err);
+                    }
+                }
+                stream.get_ref().shutdown(how)
+            }
+            #[cfg(feature = "ssl")]
+            Tls(ref tls) => tls.evented().shutdown(how),
         }
     }
 }
@@ -97,6 +208,10 @@ impl io::Read for Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
             Tcp(ref mut sock) => sock.read(buf),
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(ref mut stream)) => stream.read(buf),
+            #[cfg(feature = "ssl")]
+            Tls(_) => Err(io::Error::new(WouldBlock, "TLS negotiation has not finished.")),
         }
     }
 }
@@ -105,12 +220,152 @@ impl io::Write for Stream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match *self {
             Tcp(ref mut sock) => sock.write(buf),
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(ref mut stream)) => stream.write(buf),
+            #[cfg(feature = "ssl")]
+            Tls(_) => Err(io::Error::new(WouldBlock, "TLS negotiation has not finished.")),
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Tcp(ref mut sock) => sock.flush(),
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(ref mut stream)) => stream.flush(),
+            #[cfg(feature = "ssl")]
+            Tls(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl TlsStream {
+    fn evented(&self) -> &TcpStream {
+        match *self {
+            TlsStream::Live(ref stream) => stream.get_ref(),
+            TlsStream::Negotiating(ref mid) => mid.get_ref(),
+            TlsStream::Upgrading => panic!("Tried to access actual socket while negotiating TLS."),
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[cfg(feature = "ssl")]
+    fn self_signed() -> (::openssl::x509::X509, ::openssl::pkey::PKey<::openssl::pkey::Private>) {
+        use openssl::asn1::Asn1Time;
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::x509::{X509, X509NameBuilder};
+        use openssl::x509::extension::SubjectAlternativeName;
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost").build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn tls_negotiation() {
+        use openssl::ssl::{SslAcceptor, SslConnector, SslMethod};
+        use std::net::TcpListener;
+
+        let (cert, key) = self_signed();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.cert_store_mut().add_cert(cert).unwrap();
+        let connector = connector.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let server = TcpStream::from_stream(server).unwrap();
+
+        let mut client = Stream::tls_client(&connector, "localhost", client).unwrap();
+        let mut server = Stream::tls_server(&acceptor, server).unwrap();
+        assert!(client.is_negotiating() && server.is_negotiating());
+
+        for _ in 0..500 {
+            if !client.is_negotiating() && !server.is_negotiating() {
+                break;
+            }
+            if client.is_negotiating() {
+                client.clear_negotiating().unwrap();
+            }
+            if server.is_negotiating() {
+                server.clear_negotiating().unwrap();
+            }
+            sleep(Duration::from_millis(2));
+        }
+        assert!(!client.is_negotiating() && !server.is_negotiating());
+
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        for _ in 0..500 {
+            match server.read_exact(&mut buf) {
+                Ok(()) => break,
+                Err(ref err) if err.kind() == WouldBlock => sleep(Duration::from_millis(2)),
+                Err(err) => panic!("{}", err),
+            }
+        }
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn tls_untrusted_certificate() {
+        use openssl::ssl::{SslAcceptor, SslConnector, SslMethod};
+        use std::net::TcpListener;
+
+        let (cert, key) = self_signed();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+        let connector = SslConnector::builder(SslMethod::tls()).unwrap().build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let server = TcpStream::from_stream(server).unwrap();
+
+        let mut client = Stream::tls_client(&connector, "localhost", client).unwrap();
+        let mut server = Stream::tls_server(&acceptor, server).unwrap();
+
+        let mut failed = false;
+        for _ in 0..500 {
+            if client.is_negotiating() && client.clear_negotiating().is_err() {
+                failed = true;
+                break;
+            }
+            if server.is_negotiating() {
+                let _ = server.clear_negotiating();
+            }
+            sleep(Duration::from_millis(2));
         }
+        assert!(failed);
     }
 }
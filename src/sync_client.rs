@@ -0,0 +1,229 @@
+//! A blocking client for scripts and tools that have no use for callbacks.
+//!
+//! The event loop of a `SyncClient` runs on a helper thread. Its events come through the
+//! channel of `Builder::build_events` and the client commands the loop through its `Sender`.
+//! The helper thread drops the socket as soon as the loop stops, which ends the channel, so a
+//! client never waits on a loop that is gone.
+
+use super::Builder;
+use communication::Sender;
+use events::{Event, Outbox};
+use message::Message;
+use protocol::CloseCode;
+use result::{Result, Error, Kind};
+use std::fmt;
+use std::io;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// A client connection whose calls block until the event loop answers.
+pub struct SyncClient {
+    sender: Sender,
+    events: Receiver<Event>,
+    outbox: Outbox,
+    thread: Option<thread::JoinHandle<Result<()>>>,
+    closed: bool,
+}
+
+impl SyncClient {
+    /// Connect to `url` with the default settings and wait until the connection is open.
+    pub fn connect<U: Into<String>>(url: U) -> Result<SyncClient> {
+        SyncClient::connect_with(&Builder::new(), url)
+    }
+
+    /// Connect to `url` with the settings and codec of `builder` and wait until the connection
+    /// is open. Set `Settings::connect_timeout` to bound the wait.
+    pub fn connect_with<U: Into<String>>(builder: &Builder, url: U) -> Result<SyncClient> {
+        let (mut socket, outbox, events) = builder.build_events()?;
+        socket.connect(url.into())?;
+        // the socket owns the sending end of `events`, dropping it with the loop ends the channel
+        let thread = thread::Builder::new()
+            .name("xnet-sync-client".into())
+            .spawn(move || socket.run().map(|_| ()))?;
+
+        let opened = match events.recv() {
+            Ok(Event::Opened(id, _)) => outbox.sender(id),
+            Ok(Event::Error(_, err)) => Err(err),
+            Ok(event) => Err(Error::new(Kind::Internal, format!("Connection failed with {:?}.", event))),
+            Err(_) => Err(Error::new(Kind::Internal, "The event loop stopped before the connection opened.")),
+        };
+        match opened {
+            Ok(sender) => Ok(SyncClient {
+                sender,
+                events,
+                outbox,
+                thread: Some(thread),
+                closed: false,
+            }),
+            Err(err) => {
+                let _ = outbox.shutdown();
+                let _ = thread.join();
+                Err(err)
+            }
+        }
+    }
+
+    /// Queue a message for the other endpoint.
+    pub fn send<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        self.sender.send(msg)
+    }
+
+    /// Wait for the next message.
+    pub fn recv(&mut self) -> Result<Message> {
+        loop {
+            if self.closed {
+                return Err(Error::new(Kind::Internal, "The connection is closed."));
+            }
+            match self.events.recv() {
+                Ok(event) => {
+                    if let Some(res) = self.take(event) {
+                        return res;
+                    }
+                }
+                Err(_) => self.closed = true,
+            }
+        }
+    }
+
+    /// Wait at most `timeout` for the next message. Running out of time is an `Io` error of the
+    /// kind `TimedOut`, the connection stays usable.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Message> {
+        let deadline = ::std::time::Instant::now() + timeout;
+        loop {
+            if self.closed {
+                return Err(Error::new(Kind::Internal, "The connection is closed."));
+            }
+            let left = deadline.saturating_duration_since(::std::time::Instant::now());
+            match self.events.recv_timeout(left) {
+                Ok(event) => {
+                    if let Some(res) = self.take(event) {
+                        return res;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::new(Kind::Io(io::Error::new(io::ErrorKind::TimedOut, "recv timed out")),
+                                          "No message arrived in time."))
+                }
+                Err(RecvTimeoutError::Disconnected) => self.closed = true,
+            }
+        }
+    }
+
+    /// Close the connection with `code` and wait until the close handshake is done.
+    pub fn close(mut self, code: CloseCode) -> Result<()> {
+        if !self.closed {
+            self.sender.close(code)?;
+            while let Ok(event) = self.events.recv() {
+                if let Event::Closed(..) = event {
+                    break;
+                }
+            }
+        }
+        match self.thread.take() {
+            Some(thread) => {
+                let _ = self.outbox.shutdown();
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err(Error::new(Kind::Internal, "The event loop panicked.")))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // the result the event makes of a receive, `None` for events that are not of interest
+    fn take(&mut self, event: Event) -> Option<Result<Message>> {
+        match event {
+            Event::Message(_, msg) => Some(Ok(msg)),
+            Event::Error(_, err) => Some(Err(err)),
+            Event::Closed(_, code, reason) => {
+                self.closed = true;
+                Some(Err(Error::new(Kind::Internal, format!("The connection closed with {:?} {}", code, reason))))
+            }
+            Event::Opened(..) | Event::Timeout(..) => None,
+        }
+    }
+}
+
+impl Drop for SyncClient {
+    fn drop(&mut self) {
+        if self.thread.take().is_some() {
+            let _ = self.outbox.shutdown();
+        }
+    }
+}
+
+impl fmt::Debug for SyncClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SyncClient")
+            .field("connection_id", &self.sender.connection_id())
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+
+    use super::*;
+    use handler::Handler;
+
+    struct Echo {
+        out: Sender,
+    }
+
+    impl Handler for Echo {
+        fn on_message(&mut self, msg: Message) -> Result<()> {
+            self.out.send(msg)
+        }
+    }
+
+    #[test]
+    fn echo_round_trip() {
+        let server = Builder::new()
+            .build(|out| Echo { out })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut client = SyncClient::connect(addr.to_string()).unwrap();
+        client.send("ping").unwrap();
+        assert_eq!(client.recv().unwrap(), Message::text("ping"));
+        match client.recv_timeout(Duration::from_millis(50)) {
+            Err(Error { kind: Kind::Io(ref err), .. }) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            res => panic!("unexpected {:?}", res),
+        }
+        client.close(CloseCode::Normal).unwrap();
+
+        server.shutdown().unwrap();
+        assert!(server.join().is_ok());
+    }
+
+    #[test]
+    fn recv_after_loop_stopped() {
+        let server = Builder::new()
+            .build(|out| Echo { out })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // the loop stops right away without reporting the connection as closed
+        let mut client = SyncClient::connect(addr.to_string()).unwrap();
+        client.outbox.shutdown().unwrap();
+        assert!(client.recv().is_err());
+        assert!(client.recv_timeout(Duration::from_secs(5)).is_err());
+        client.close(CloseCode::Normal).unwrap();
+
+        server.shutdown().unwrap();
+        assert!(server.join().is_ok());
+    }
+}